@@ -1 +1 @@
-CONV=1 cargo run --release < input.txt
+cargo run --release -- eval input.txt
@@ -0,0 +1,170 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::{
+    parser::{try_parse_string, Token},
+    protocol::ProtocolError,
+    workspace::Workspace,
+};
+
+// On-disk store of server responses. A response is saved in
+// `<dir>/<key>.txt`, where the key is the hash of the request, and every
+// recorded exchange is appended to `<dir>/requests.jsonl`:
+//
+//     {"key": "…", "request": "get spaceship1", "time": 1720000000}
+//
+// Requests that are plain strings are keyed and logged decoded, others (e.g.
+// compressed solutions) as the ICFP text.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Send the requests and save the responses.
+    Record,
+    /// Answer from the saved responses without network.
+    Replay,
+}
+
+impl FromStr for CacheMode {
+    type Err = ProtocolError;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "record" => Ok(CacheMode::Record),
+            "replay" => Ok(CacheMode::Replay),
+            _ => Err(ProtocolError::InvalidCacheMode(mode.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    pub mode: CacheMode,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>, mode: CacheMode) -> Self {
+        Self {
+            dir: dir.into(),
+            mode,
+        }
+    }
+
+    /// `CACHE=record|replay`, in `CACHE_DIR` or the cache directory of the
+    /// workspace. `None` when `CACHE` is not set.
+    pub fn from_env() -> Result<Option<Self>, ProtocolError> {
+        let Ok(mode) = dotenv::var("CACHE") else {
+            return Ok(None);
+        };
+        let mode = mode.parse()?;
+        let dir = match dotenv::var("CACHE_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => Workspace::from_env().cache_dir(),
+        };
+        Ok(Some(Self::new(dir, mode)))
+    }
+
+    fn path(&self, request: &str) -> PathBuf {
+        self.dir.join(format!("{}.txt", key(&message(request))))
+    }
+
+    pub fn get(&self, request: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.path(request)) {
+            Ok(response) => Ok(Some(response)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn record(&self, request: &str, response: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(request), response)?;
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let request = message(request);
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("requests.jsonl"))?;
+        writeln!(
+            log,
+            "{{\"key\": \"{}\", \"request\": {}, \"time\": {}}}",
+            key(&request),
+            json_string(&request),
+            time
+        )
+    }
+}
+
+/// The request as text if it is a plain string, otherwise the ICFP.
+pub fn message(request: &str) -> String {
+    match try_parse_string(request) {
+        Ok(Token::String(msg)) => String::from_utf8_lossy(&msg).into_owned(),
+        _ => request.to_string(),
+    }
+}
+
+// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`.
+fn key(request: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in request.as_bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[tokio::test]
+async fn record_and_replay() {
+    use crate::{mock_server::MockServer, protocol::Client};
+
+    assert_eq!("replay".parse::<CacheMode>().unwrap(), CacheMode::Replay);
+    assert_eq!(
+        "Record".parse::<CacheMode>().unwrap_err().to_string(),
+        "Invalid CACHE: Record, expected record or replay"
+    );
+
+    let dir = std::env::temp_dir().join(format!("cache_{}", std::process::id()));
+    let url = MockServer::new(Workspace::default()).spawn().await.unwrap();
+    let recorder = Client::new(&url, "").with_cache(Cache::new(&dir, CacheMode::Record));
+    let hello = recorder.echo("Hello \"World\"").await.unwrap();
+    let task = recorder.get("spaceship1").await.unwrap();
+
+    // nothing listens on the port, all responses must come from the cache
+    let replayer =
+        Client::new("http://127.0.0.1:1", "").with_cache(Cache::new(&dir, CacheMode::Replay));
+    assert_eq!(replayer.echo("Hello \"World\"").await.unwrap(), hello);
+    assert_eq!(replayer.get("spaceship1").await.unwrap(), task);
+    assert!(matches!(
+        replayer.get("spaceship2").await,
+        Err(ProtocolError::NotCached(request)) if request == "get spaceship2"
+    ));
+
+    let log = fs::read_to_string(dir.join("requests.jsonl")).unwrap();
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(r#""request": "echo Hello \"World\"", "time": "#));
+    assert!(lines[1].starts_with(&format!("{{\"key\": \"{}\"", key("get spaceship1"))));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,292 @@
+use std::{ops::Range, path::PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{parser::Strategy, trace::parse_range};
+
+pub const USAGE: &str = "\
+Usage: borys <command> [args]
+
+  eval [options] [file]           evaluate ICFP text (stdin by default)
+      --strategy name|need|memo   (default name)
+      --beta-limit <n>
+      --simplify                  simplify before evaluating
+      --decompile                 print the decompiled program
+      --trace <file>|-            log the reduction steps
+      --trace-depth <from>..<to>
+      --trace-steps <from>..<to>
+  encode [text]                   encode the text as an ICFP string (stdin by default)
+  compile [file]                  compile the source language to ICFP
+  analyze [file]                  summarize an efficiency program
+  repl                            evaluate expressions interactively
+  send <message>                  send `get`, `solve` or `echo` to the server
+  queue [flush]                   list the queued submissions, or send them
+  download <family> [<from>..<to>]
+                                  fetch spaceship, lambdaman, 3d or efficiency tasks
+  mock-server [address]           serve the tasks locally (default 127.0.0.1:8000)
+  spaceship solve|draw|viz|submit <id>
+  lambdaman solve|viz <id>
+  lambdaman compress <id> [file]  compress the moves in the file (stdin by default)
+
+The tasks and solutions are in DATA_DIR (the parent of the borys crate by
+default). The server settings are read from TOKEN, BASE_URL, CACHE and
+CACHE_DIR, the submission rate limit from SUBMIT_INTERVAL_MS, SUBMIT_RETRIES
+and SUBMIT_BACKOFF_MS.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalOptions {
+    pub input: Option<PathBuf>,
+    pub strategy: Strategy,
+    pub beta_limit: Option<usize>,
+    pub simplify: bool,
+    pub decompile: bool,
+    pub trace: Option<String>,
+    pub trace_depth: Option<Range<usize>>,
+    pub trace_steps: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceshipAction {
+    Solve,
+    Draw,
+    Viz,
+    Submit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LambdamanAction {
+    Solve,
+    Viz,
+    Compress,
+}
+
+/// `None` inputs are read from stdin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Eval(EvalOptions),
+    Encode(Option<String>),
+    Compile(Option<PathBuf>),
+    Analyze(Option<PathBuf>),
+    Repl,
+    Send(String),
+    /// Lists the pending submissions, or sends them with `flush`.
+    Queue {
+        flush: bool,
+    },
+    Download {
+        family: String,
+        ids: Option<Range<usize>>,
+    },
+    MockServer(String),
+    Spaceship(SpaceshipAction, usize),
+    Lambdaman {
+        action: LambdamanAction,
+        id: usize,
+        input: Option<PathBuf>,
+    },
+    Help,
+}
+
+/// Parses the arguments without the program name.
+pub fn parse_args(args: &[String]) -> Result<Command> {
+    let Some((command, args)) = args.split_first() else {
+        return Ok(Command::Help);
+    };
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let optional_path = |args: &[&str]| match args {
+        [] => Ok(None),
+        [path] => Ok(Some(PathBuf::from(path))),
+        _ => Err(anyhow!("Unexpected arguments: {}", args[1..].join(" "))),
+    };
+    let id = |arg: Option<&&str>| -> Result<usize> {
+        let arg = arg.ok_or_else(|| anyhow!("Missing the task id"))?;
+        arg.parse().map_err(|_| anyhow!("Invalid task id: {}", arg))
+    };
+    Ok(match command.as_str() {
+        "eval" => Command::Eval(eval_options(&args)?),
+        "encode" => Command::Encode(match args[..] {
+            [] => None,
+            _ => Some(args.join(" ")),
+        }),
+        "compile" => Command::Compile(optional_path(&args)?),
+        "analyze" => Command::Analyze(optional_path(&args)?),
+        "repl" => Command::Repl,
+        "send" if args.is_empty() => bail!("Missing the message"),
+        "send" => Command::Send(args.join(" ")),
+        "queue" => Command::Queue {
+            flush: match args[..] {
+                [] => false,
+                ["flush"] => true,
+                _ => bail!("Expected flush"),
+            },
+        },
+        "download" => {
+            let (family, ids) = match args[..] {
+                [family] => (family, None),
+                [family, ids] => (family, Some(range(ids)?)),
+                _ => bail!("Expected a family and optionally the ids"),
+            };
+            Command::Download {
+                family: family.to_string(),
+                ids,
+            }
+        }
+        "mock-server" => Command::MockServer(match args[..] {
+            [] => "127.0.0.1:8000".to_string(),
+            [addr] => addr.to_string(),
+            _ => bail!("Expected an address"),
+        }),
+        "spaceship" => {
+            let action = match args.first() {
+                Some(&"solve") => SpaceshipAction::Solve,
+                Some(&"draw") => SpaceshipAction::Draw,
+                Some(&"viz") => SpaceshipAction::Viz,
+                Some(&"submit") => SpaceshipAction::Submit,
+                _ => bail!("Expected solve, draw, viz or submit"),
+            };
+            if args.len() > 2 {
+                bail!("Unexpected arguments: {}", args[2..].join(" "));
+            }
+            Command::Spaceship(action, id(args.get(1))?)
+        }
+        "lambdaman" => {
+            let action = match args.first() {
+                Some(&"solve") => LambdamanAction::Solve,
+                Some(&"viz") => LambdamanAction::Viz,
+                Some(&"compress") => LambdamanAction::Compress,
+                _ => bail!("Expected solve, viz or compress"),
+            };
+            let rest = args.get(2..).unwrap_or(&[]);
+            let input = match action {
+                LambdamanAction::Compress => optional_path(rest)?,
+                _ if !rest.is_empty() => bail!("Unexpected arguments: {}", rest.join(" ")),
+                _ => None,
+            };
+            let id = id(args.get(1))?;
+            Command::Lambdaman { action, id, input }
+        }
+        "help" | "-h" | "--help" => Command::Help,
+        _ => bail!("Unknown command: {}", command),
+    })
+}
+
+fn eval_options(mut args: &[&str]) -> Result<EvalOptions> {
+    let mut options = EvalOptions {
+        input: None,
+        strategy: Strategy::Name,
+        beta_limit: None,
+        simplify: false,
+        decompile: false,
+        trace: None,
+        trace_depth: None,
+        trace_steps: None,
+    };
+    while let Some((&arg, rest)) = args.split_first() {
+        args = rest;
+        let mut value = || -> Result<&str> {
+            let (&value, rest) = args
+                .split_first()
+                .ok_or_else(|| anyhow!("Missing the value of {}", arg))?;
+            args = rest;
+            Ok(value)
+        };
+        match arg {
+            "--strategy" => {
+                options.strategy = match value()? {
+                    "name" => Strategy::Name,
+                    "need" => Strategy::Need,
+                    "memo" => Strategy::Memo,
+                    strategy => bail!("Unknown strategy: {}", strategy),
+                }
+            }
+            "--beta-limit" => {
+                let limit = value()?;
+                options.beta_limit = Some(
+                    limit
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid beta limit: {}", limit))?,
+                );
+            }
+            "--simplify" => options.simplify = true,
+            "--decompile" => options.decompile = true,
+            "--trace" => options.trace = Some(value()?.to_string()),
+            "--trace-depth" => options.trace_depth = Some(range(value()?)?),
+            "--trace-steps" => options.trace_steps = Some(range(value()?)?),
+            _ if arg.starts_with("--") => bail!("Unknown option: {}", arg),
+            _ if options.input.is_none() => options.input = Some(PathBuf::from(arg)),
+            _ => bail!("Unexpected argument: {}", arg),
+        }
+    }
+    Ok(options)
+}
+
+fn range(s: &str) -> Result<Range<usize>> {
+    parse_range(s).ok_or_else(|| anyhow!("Invalid range: {}, expected <from>..<to>", s))
+}
+
+#[test]
+fn cli_args() {
+    let parse = |line: &str| {
+        let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        parse_args(&args).map_err(|err| err.to_string())
+    };
+    assert_eq!(parse(""), Ok(Command::Help));
+    assert_eq!(
+        parse("spaceship submit 12"),
+        Ok(Command::Spaceship(SpaceshipAction::Submit, 12))
+    );
+    assert_eq!(
+        parse("lambdaman compress 5 moves.txt"),
+        Ok(Command::Lambdaman {
+            action: LambdamanAction::Compress,
+            id: 5,
+            input: Some(PathBuf::from("moves.txt"))
+        })
+    );
+    assert_eq!(
+        parse("download efficiency 3..6"),
+        Ok(Command::Download {
+            family: "efficiency".to_string(),
+            ids: Some(3..6)
+        })
+    );
+    assert_eq!(
+        parse("send solve spaceship1 31619"),
+        Ok(Command::Send("solve spaceship1 31619".to_string()))
+    );
+    assert_eq!(parse("queue flush"), Ok(Command::Queue { flush: true }));
+    assert_eq!(parse("queue send"), Err("Expected flush".to_string()));
+
+    let Ok(Command::Eval(options)) =
+        parse("eval --strategy memo --trace - --trace-steps 10..20 prog.txt")
+    else {
+        panic!("not eval");
+    };
+    assert_eq!(options.strategy, Strategy::Memo);
+    assert!(!options.simplify);
+    assert_eq!(options.trace.as_deref(), Some("-"));
+    assert_eq!(options.trace_steps, Some(10..20));
+    assert_eq!(options.input, Some(PathBuf::from("prog.txt")));
+
+    assert_eq!(
+        parse("spaceship solve"),
+        Err("Missing the task id".to_string())
+    );
+    assert_eq!(
+        parse("spaceship fly 1"),
+        Err("Expected solve, draw, viz or submit".to_string())
+    );
+    assert_eq!(
+        parse("lambdaman viz x"),
+        Err("Invalid task id: x".to_string())
+    );
+    assert_eq!(
+        parse("eval --beta-limit"),
+        Err("Missing the value of --beta-limit".to_string())
+    );
+    assert_eq!(
+        parse("eval --fast"),
+        Err("Unknown option: --fast".to_string())
+    );
+    assert_eq!(parse("fly"), Err("Unknown command: fly".to_string()));
+}
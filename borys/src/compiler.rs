@@ -0,0 +1,524 @@
+use std::{fmt, rc::Rc};
+
+use num_bigint::BigInt;
+
+use crate::parser::{BinaryOp, Token, UnaryOp, ALPH};
+
+// Compiles the language printed by the decompiler back to ICFP terms:
+//
+// let rec fact n = if n == 1 then 1 else n * fact (n - 1) in
+// let greet name = "Hello " ++ name in
+// greet "World" ++ int_to_str (fact 5)
+//
+// `let rec` is compiled with the Y combinator. Binders are numbered by their
+// nesting depth, so the output uses the shortest variable names possible.
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Compile error at {}:{}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+pub fn compile(source: &str) -> Result<Token, CompileError> {
+    compile_with(source, &[])
+}
+
+/// Compiles `source` in which `globals` can be used, the i-th one is the free
+/// variable `i` of the result.
+pub fn compile_with(source: &str, globals: &[String]) -> Result<Token, CompileError> {
+    let lexemes = lex(source)?;
+    let mut compiler = Compiler {
+        source,
+        lexemes,
+        pos: 0,
+        scope: globals.iter().cloned().zip(0..).collect(),
+    };
+    let res = compiler.expr()?;
+    if compiler.peek() != &Lexeme::End {
+        return Err(compiler.error("Expected end of input"));
+    }
+    Ok(res)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lexeme {
+    Int(BigInt),
+    String(Vec<u8>),
+    Ident(String),
+    Symbol(&'static str),
+    End,
+}
+
+// Two-character symbols go first so that `==` is not lexed as `=` `=`.
+const SYMBOLS: [&str; 16] = [
+    "->", "==", "||", "&&", "++", "(", ")", "=", "<", ">", "+", "-", "*", "/", "%", "!",
+];
+
+const KEYWORDS: [&str; 9] = [
+    "let", "rec", "in", "fun", "if", "then", "else", "true", "false",
+];
+
+const BUILTINS: [(&str, usize); 4] = [
+    ("str_to_int", 1),
+    ("int_to_str", 1),
+    ("take", 2),
+    ("drop", 2),
+];
+
+fn error_at(source: &str, offset: usize, message: String) -> CompileError {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = offset - before.rfind('\n').map_or(0, |pos| pos + 1) + 1;
+    CompileError {
+        line,
+        column,
+        message,
+    }
+}
+
+// Returns lexemes with their byte offsets, `//` starts a comment.
+fn lex(source: &str) -> Result<Vec<(Lexeme, usize)>, CompileError> {
+    let bytes = source.as_bytes();
+    let mut res = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        let c = bytes[pos];
+        if c.is_ascii_whitespace() {
+            pos += 1;
+        } else if source[pos..].starts_with("//") {
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+        } else if c.is_ascii_digit() {
+            while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+                pos += 1;
+            }
+            res.push((Lexeme::Int(source[start..pos].parse().unwrap()), start));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while pos < bytes.len()
+                && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_' || bytes[pos] == b'\'')
+            {
+                pos += 1;
+            }
+            res.push((Lexeme::Ident(source[start..pos].to_string()), start));
+        } else if c == b'"' {
+            pos += 1;
+            let mut s = vec![];
+            loop {
+                let c = match bytes.get(pos) {
+                    None => return Err(error_at(source, start, "Unterminated string".to_string())),
+                    Some(b'"') => break,
+                    Some(b'\\') => {
+                        pos += 1;
+                        match bytes.get(pos) {
+                            Some(b'n') => b'\n',
+                            Some(&c @ (b'"' | b'\\')) => c,
+                            _ => return Err(error_at(source, pos, "Invalid escape".to_string())),
+                        }
+                    }
+                    Some(&c) => c,
+                };
+                if !ALPH.as_bytes().contains(&c) {
+                    let message = format!("Character {:?} can't be encoded", c as char);
+                    return Err(error_at(source, pos, message));
+                }
+                s.push(c);
+                pos += 1;
+            }
+            pos += 1;
+            res.push((Lexeme::String(s), start));
+        } else {
+            let Some(symbol) = SYMBOLS.iter().find(|s| source[pos..].starts_with(*s)) else {
+                let message = format!("Unexpected character {:?}", c as char);
+                return Err(error_at(source, pos, message));
+            };
+            pos += symbol.len();
+            res.push((Lexeme::Symbol(symbol), start));
+        }
+    }
+    res.push((Lexeme::End, source.len()));
+    Ok(res)
+}
+
+fn abstraction(params: &[usize], body: Token) -> Token {
+    params
+        .iter()
+        .rev()
+        .fold(body, |body, &var| Token::Abstraction(var, Rc::new(body)))
+}
+
+fn application(f: Token, arg: Token) -> Token {
+    Token::Application(Rc::new(f), Rc::new(arg))
+}
+
+// Binary operators by precedence level, from the loosest one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+const LEVELS: [(Assoc, &[(&str, BinaryOp)]); 6] = [
+    (Assoc::Right, &[("||", BinaryOp::Or)]),
+    (Assoc::Right, &[("&&", BinaryOp::And)]),
+    (
+        Assoc::None,
+        &[
+            ("==", BinaryOp::Eq),
+            ("<", BinaryOp::Less),
+            (">", BinaryOp::More),
+        ],
+    ),
+    (Assoc::Right, &[("++", BinaryOp::Concat)]),
+    (Assoc::Left, &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)]),
+    (
+        Assoc::Left,
+        &[
+            ("*", BinaryOp::Mul),
+            ("/", BinaryOp::Div),
+            ("%", BinaryOp::Mod),
+        ],
+    ),
+];
+
+struct Compiler<'a> {
+    source: &'a str,
+    lexemes: Vec<(Lexeme, usize)>,
+    pos: usize,
+    // names in scope and their variables, the last entry wins
+    scope: Vec<(String, usize)>,
+}
+
+impl Compiler<'_> {
+    fn peek(&self) -> &Lexeme {
+        &self.lexemes[self.pos].0
+    }
+
+    fn next(&mut self) -> Lexeme {
+        let res = self.lexemes[self.pos].0.clone();
+        if res != Lexeme::End {
+            self.pos += 1;
+        }
+        res
+    }
+
+    fn error(&self, message: &str) -> CompileError {
+        let message = match self.peek() {
+            Lexeme::End => format!("{}, got end of input", message),
+            lexeme => format!("{}, got {:?}", message, lexeme),
+        };
+        error_at(self.source, self.lexemes[self.pos].1, message)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Lexeme::Ident(s) if s == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let res = self.is_keyword(keyword);
+        if res {
+            self.pos += 1;
+        }
+        res
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), CompileError> {
+        if !self.eat_keyword(keyword) {
+            return Err(self.error(&format!("Expected `{}`", keyword)));
+        }
+        Ok(())
+    }
+
+    fn peek_symbol(&self) -> Option<&'static str> {
+        match self.peek() {
+            Lexeme::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.peek_symbol() != Some(symbol) {
+            return Err(self.error(&format!("Expected `{}`", symbol)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // A name that can be bound, i.e. not a keyword or a builtin.
+    fn peek_name(&self) -> Option<&str> {
+        match self.peek() {
+            Lexeme::Ident(s)
+                if !KEYWORDS.contains(&s.as_str()) && !BUILTINS.iter().any(|(b, _)| b == s) =>
+            {
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn name(&mut self) -> Result<String, CompileError> {
+        let Some(name) = self.peek_name() else {
+            return Err(self.error("Expected a name"));
+        };
+        let name = name.to_string();
+        self.pos += 1;
+        Ok(name)
+    }
+
+    fn params(&mut self) -> Vec<String> {
+        let mut params = vec![];
+        while let Some(name) = self.peek_name() {
+            params.push(name.to_string());
+            self.pos += 1;
+        }
+        params
+    }
+
+    fn bind(&mut self, name: String) -> usize {
+        let var = self.scope.len();
+        self.scope.push((name, var));
+        var
+    }
+
+    fn expr(&mut self) -> Result<Token, CompileError> {
+        if self.eat_keyword("let") {
+            self.let_expr()
+        } else if self.eat_keyword("fun") {
+            let scope = self.scope.len();
+            let params = self.params();
+            if params.is_empty() {
+                return Err(self.error("Expected a name"));
+            }
+            self.expect_symbol("->")?;
+            let vars: Vec<_> = params.into_iter().map(|name| self.bind(name)).collect();
+            let body = self.expr()?;
+            self.scope.truncate(scope);
+            Ok(abstraction(&vars, body))
+        } else if self.eat_keyword("if") {
+            let cond = self.expr()?;
+            self.expect_keyword("then")?;
+            let first = self.expr()?;
+            self.expect_keyword("else")?;
+            let second = self.expr()?;
+            Ok(Token::If(Rc::new(cond), Rc::new(first), Rc::new(second)))
+        } else {
+            self.binary(0)
+        }
+    }
+
+    // `let [rec] name params = value in body`, after the `let`.
+    fn let_expr(&mut self) -> Result<Token, CompileError> {
+        let rec = self.eat_keyword("rec");
+        let name = self.name()?;
+        let params = self.params();
+        self.expect_symbol("=")?;
+        let scope = self.scope.len();
+        // the function sees itself under the same variable as the body does
+        let var = scope;
+        if rec {
+            self.bind(name.clone());
+        } else {
+            // keep the numbering the same as with `rec`
+            self.scope.push((String::new(), var));
+        }
+        let vars: Vec<_> = params.into_iter().map(|name| self.bind(name)).collect();
+        let mut value = abstraction(&vars, self.expr()?);
+        if rec {
+            let y = Token::y_combinator(var, var + 1);
+            value = application(y, Token::Abstraction(var, Rc::new(value)));
+        }
+        self.scope.truncate(scope);
+        self.expect_keyword("in")?;
+        self.bind(name);
+        let body = self.expr()?;
+        self.scope.truncate(scope);
+        if rec && body == Token::Id(var) {
+            return Ok(value);
+        }
+        Ok(application(Token::Abstraction(var, Rc::new(body)), value))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Token, CompileError> {
+        let Some(&(assoc, ops)) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut res = self.binary(level + 1)?;
+        while let Some(symbol) = self.peek_symbol() {
+            let Some((_, op)) = ops.iter().find(|(s, _)| *s == symbol) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = match assoc {
+                Assoc::Right => self.binary(level)?,
+                Assoc::Left | Assoc::None => self.binary(level + 1)?,
+            };
+            res = Token::BinaryOp(op.clone(), Rc::new(res), Rc::new(rhs));
+            if assoc != Assoc::Left {
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    fn unary(&mut self) -> Result<Token, CompileError> {
+        let op = match self.peek_symbol() {
+            Some("-") => UnaryOp::NegInteger,
+            Some("!") => UnaryOp::Not,
+            _ => return self.application(),
+        };
+        self.pos += 1;
+        Ok(Token::UnaryOp(op, Rc::new(self.unary()?)))
+    }
+
+    fn starts_atom(&self) -> bool {
+        match self.peek() {
+            Lexeme::Int(_) | Lexeme::String(_) => true,
+            Lexeme::Ident(_) => {
+                self.peek_name().is_some() || self.is_keyword("true") || self.is_keyword("false")
+            }
+            Lexeme::Symbol(s) => *s == "(",
+            Lexeme::End => false,
+        }
+    }
+
+    fn application(&mut self) -> Result<Token, CompileError> {
+        let builtin = match self.peek() {
+            Lexeme::Ident(s) => BUILTINS.iter().find(|(b, _)| b == s).copied(),
+            _ => None,
+        };
+        let mut res = match builtin {
+            Some((name, args)) => {
+                self.pos += 1;
+                let mut operands = vec![];
+                for _ in 0..args {
+                    if !self.starts_atom() {
+                        return Err(self.error(&format!("`{}` expects {} arguments", name, args)));
+                    }
+                    operands.push(Rc::new(self.atom()?));
+                }
+                match name {
+                    "str_to_int" => Token::UnaryOp(UnaryOp::StringToInt, operands[0].clone()),
+                    "int_to_str" => Token::UnaryOp(UnaryOp::IntToString, operands[0].clone()),
+                    "take" => {
+                        Token::BinaryOp(BinaryOp::Prefix, operands[0].clone(), operands[1].clone())
+                    }
+                    _ => Token::BinaryOp(BinaryOp::Drop, operands[0].clone(), operands[1].clone()),
+                }
+            }
+            None => self.atom()?,
+        };
+        while self.starts_atom() {
+            res = application(res, self.atom()?);
+        }
+        Ok(res)
+    }
+
+    fn atom(&mut self) -> Result<Token, CompileError> {
+        if self.peek_symbol() == Some("(") {
+            self.pos += 1;
+            let res = self.expr()?;
+            self.expect_symbol(")")?;
+            return Ok(res);
+        }
+        let res = match self.peek() {
+            Lexeme::Int(i) => Token::Int(i.clone()),
+            Lexeme::String(s) => Token::String(s.clone()),
+            Lexeme::Ident(s) if s == "true" => Token::Bool(true),
+            Lexeme::Ident(s) if s == "false" => Token::Bool(false),
+            Lexeme::Ident(s) if self.peek_name().is_some() => {
+                match self.scope.iter().rev().find(|(name, _)| name == s) {
+                    Some(&(_, var)) => Token::Id(var),
+                    None => return Err(self.error("Unknown variable")),
+                }
+            }
+            _ => return Err(self.error("Expected an expression")),
+        };
+        self.next();
+        Ok(res)
+    }
+}
+
+#[test]
+fn compile_simple() {
+    use crate::parser::encode_token;
+
+    let compiled = |source: &str| encode_token(&compile(source).unwrap());
+    assert_eq!(compiled(r#""Hello" ++ " World!""#), "B. SB%,,/ S}Q/2,$_");
+    assert_eq!(compiled("1 - 2 - 3"), "B- B- I\" I# I$");
+    assert_eq!(compiled("-2 * 3 < 4 && !true"), "B& B< B* U- I# I$ I% U! T");
+    assert_eq!(compiled("fun x y -> x"), "L! L\" v!");
+    assert_eq!(compiled("let x = 3 in x + x"), "B$ L! B+ v! v! I$");
+    assert_eq!(compiled("take 2 (drop 1 \"abc\")"), "BT I# BD I\" S!\"#");
+    assert_eq!(
+        compiled("let rec f x = f x in f"),
+        "B$ L! B$ L\" B$ v! B$ v\" v\" L\" B$ v! B$ v\" v\" L! L\" B$ v! v\""
+    );
+}
+
+#[test]
+fn compile_eval() {
+    use crate::parser::{eval, parse_string};
+
+    let run = |source: &str| eval(&compile(source).unwrap());
+    let fact = "let rec fact n = if n == 1 then 1 else n * fact (n - 1) in fact 5";
+    assert_eq!(run(fact), Token::Int(120.into()));
+    let source = r#"
+        // Euclid's algorithm
+        let rec gcd a b = if b == 0 then a else gcd b (a % b) in
+        let greet name = "Hello " ++ name in
+        greet (take 5 "World!!") ++ int_to_str (gcd 84 36)
+    "#;
+    // strings are base-94 numbers, 12 is "m"
+    assert_eq!(run(source), Token::String(b"Hello Worldm".to_vec()));
+    let source = "let x = 1 in let f y = x + y in let x = 10 in f x";
+    assert_eq!(run(source), Token::Int(11.into()));
+    let source = "let twice f x = f (f x) in twice (fun s -> s ++ s) \"ab\"";
+    assert_eq!(run(source), Token::String(b"abababab".to_vec()));
+
+    // decompiled programs compile to equivalent ones
+    let program = parse_string(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+    );
+    let source = crate::decompiler::decompile(&program);
+    assert_eq!(run(&source), eval(&program));
+}
+
+#[test]
+fn compile_errors() {
+    let error = |source: &str| compile(source).unwrap_err().to_string();
+    assert_eq!(
+        error("let x = 1 in\ny + 1"),
+        "Compile error at 2:1: Unknown variable, got Ident(\"y\")"
+    );
+    assert_eq!(
+        error("1 +"),
+        "Compile error at 1:4: Expected an expression, got end of input"
+    );
+    assert_eq!(
+        error("take 1"),
+        "Compile error at 1:7: `take` expects 2 arguments, got end of input"
+    );
+    assert_eq!(
+        error("\"{\""),
+        "Compile error at 1:2: Character '{' can't be encoded"
+    );
+    assert_eq!(
+        error("1 < 2 < 3"),
+        "Compile error at 1:7: Expected end of input, got Symbol(\"<\")"
+    );
+}
@@ -0,0 +1,555 @@
+use std::rc::Rc;
+
+use crate::parser::{children, BinaryOp, Token, UnaryOp};
+
+// Renders ICFP programs as ML-like source, e.g. the factorial test becomes
+//
+// (let rec v1 v2 = if v2 == 1 then 1 else v2 * v1 (v2 - 1) in v1) 5
+//
+// Variables keep their ICFP numbers, `B$ (L x. body) arg` is shown as
+// `let x = arg in body` and fixpoint combinators as `let rec`.
+
+const WIDTH: usize = 100;
+
+// Placeholder for the subterms left out by `decompile_truncated`.
+const ELIDED: usize = usize::MAX;
+
+// Precedence levels, a sub-expression is parenthesized when its level is lower
+// than the one required at its position.
+const LOWEST: u8 = 0; // let, fun, if
+const OR: u8 = 1;
+const AND: u8 = 2;
+const CMP: u8 = 3;
+const CONCAT: u8 = 4;
+const ADD: u8 = 5;
+const MUL: u8 = 6;
+const UNARY: u8 = 7;
+const APP: u8 = 8;
+const ATOM: u8 = 9;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Assoc {
+    Left,
+    Associative,
+    None,
+}
+
+pub fn decompile(token: &Token) -> String {
+    Decompiler { names: vec![] }.render(token, LOWEST, 0, true)
+}
+
+/// Renders `token` on a single line.
+pub fn decompile_line(token: &Token) -> String {
+    Decompiler { names: vec![] }.render(token, LOWEST, 0, false)
+}
+
+/// Renders `token` on a single line cut after `width` characters. Every node
+/// takes at least one character, so only the first `width` nodes are visited.
+pub fn decompile_truncated(token: &Token, width: usize) -> String {
+    let mut res = decompile_line(&prune(token, width));
+    if let Some((cut, _)) = res.char_indices().nth(width) {
+        res.truncate(cut);
+        res.push_str("...");
+    }
+    res
+}
+
+/// Renders `token` as a function argument, in parentheses unless it is atomic.
+pub fn decompile_arg(token: &Token) -> String {
+    Decompiler { names: vec![] }.render(token, ATOM, 0, true)
+}
+
+pub fn var_name(var: usize) -> String {
+    format!("v{}", var)
+}
+
+pub fn escape_string(s: &[u8]) -> String {
+    let mut res = String::from("\"");
+    for &c in s {
+        match c {
+            b'"' => res.push_str("\\\""),
+            b'\\' => res.push_str("\\\\"),
+            b'\n' => res.push_str("\\n"),
+            _ => res.push(c as char),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// Copy of the first `nodes` nodes of `token` in prefix order, the rest is
+// replaced by `ELIDED`. Long strings are cut to `nodes` characters as well.
+fn prune(token: &Token, nodes: usize) -> Token {
+    enum Task<'a> {
+        Visit(&'a Token),
+        Build(&'a Token),
+    }
+    let mut visited = 0;
+    let mut tasks = vec![Task::Visit(token)];
+    let mut results: Vec<Rc<Token>> = vec![];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(_) if visited == nodes => results.push(Rc::new(Token::Id(ELIDED))),
+            Task::Visit(token) => {
+                visited += 1;
+                tasks.push(Task::Build(token));
+                let children = children(token).into_iter().rev();
+                tasks.extend(children.map(|c| Task::Visit(c.as_ref())));
+            }
+            Task::Build(token) => {
+                let count = children(token).len();
+                let mut children = results.split_off(results.len() - count).into_iter();
+                let mut next = || children.next().unwrap();
+                let res = match token {
+                    Token::String(s) => Token::String(s[..s.len().min(nodes)].to_vec()),
+                    Token::UnaryOp(op, _) => Token::UnaryOp(op.clone(), next()),
+                    Token::BinaryOp(op, _, _) => Token::BinaryOp(op.clone(), next(), next()),
+                    Token::If(..) => Token::If(next(), next(), next()),
+                    Token::Application(..) => Token::Application(next(), next()),
+                    Token::Abstraction(var, _) => Token::Abstraction(*var, next()),
+                    leaf => leaf.clone(),
+                };
+                results.push(Rc::new(res));
+            }
+        }
+    }
+    Rc::unwrap_or_clone(results.pop().unwrap())
+}
+
+// Infix operators, `take` and `drop` are shown as functions.
+fn binary_info(op: &BinaryOp) -> Option<(&'static str, u8, Assoc)> {
+    Some(match op {
+        BinaryOp::Or => ("||", OR, Assoc::Associative),
+        BinaryOp::And => ("&&", AND, Assoc::Associative),
+        BinaryOp::Eq => ("==", CMP, Assoc::None),
+        BinaryOp::Less => ("<", CMP, Assoc::None),
+        BinaryOp::More => (">", CMP, Assoc::None),
+        BinaryOp::Concat => ("++", CONCAT, Assoc::Associative),
+        BinaryOp::Add => ("+", ADD, Assoc::Left),
+        BinaryOp::Sub => ("-", ADD, Assoc::Left),
+        BinaryOp::Mul => ("*", MUL, Assoc::Left),
+        BinaryOp::Div => ("/", MUL, Assoc::Left),
+        BinaryOp::Mod => ("%", MUL, Assoc::Left),
+        BinaryOp::Prefix | BinaryOp::Drop => return None,
+    })
+}
+
+fn uses_var(token: &Token, var: usize) -> bool {
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        if let Token::Id(i) | Token::Abstraction(i, _) = token {
+            if *i == var {
+                return true;
+            }
+        }
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    false
+}
+
+// Every node takes at least one character, so bigger terms can't fit a line.
+fn is_small(token: &Token) -> bool {
+    let mut stack = vec![token];
+    let mut nodes = 0;
+    while let Some(token) = stack.pop() {
+        nodes += 1;
+        if nodes > WIDTH {
+            return false;
+        }
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    true
+}
+
+// Splits `\a. \b. body` into `[a, b]` and `body`.
+pub(crate) fn params(token: &Token) -> (Vec<usize>, &Token) {
+    let mut params = vec![];
+    let mut cur = token;
+    while let Token::Abstraction(i, inner) = cur {
+        params.push(*i);
+        cur = inner;
+    }
+    (params, cur)
+}
+
+// `B$ B$ (L a. L b. body) x y` as `[(a, x), (b, y)]` and `body`. Later
+// values must not see the earlier variables, otherwise they would be captured
+// once shown as nested `let`s.
+fn let_bindings(token: &Token) -> Option<(Vec<(usize, &Token)>, &Token)> {
+    let mut values = vec![];
+    let mut head = token;
+    while let Token::Application(lhs, rhs) = head {
+        values.push(rhs.as_ref());
+        head = lhs;
+    }
+    values.reverse();
+    let mut bindings = vec![];
+    for value in values {
+        let Token::Abstraction(var, body) = head else {
+            return None;
+        };
+        if bindings.iter().any(|&(prev, _)| uses_var(value, prev)) {
+            return None;
+        }
+        bindings.push((*var, value));
+        head = body;
+    }
+    (!bindings.is_empty()).then_some((bindings, head))
+}
+
+fn header(keyword: &str, vars: &[usize]) -> String {
+    let vars: Vec<_> = vars.iter().map(|&i| var_name(i)).collect();
+    format!("{} {}", keyword, vars.join(" "))
+}
+
+fn newline(indent: usize, multiline: bool) -> String {
+    if multiline {
+        format!("\n{}", " ".repeat(indent))
+    } else {
+        " ".to_string()
+    }
+}
+
+struct Decompiler {
+    // Scoped renames of bound variables, the last entry wins. Used to show the
+    // fixpoint function under the name of the variable it is bound to.
+    names: Vec<(usize, usize)>,
+}
+
+impl Decompiler {
+    fn name(&self, var: usize) -> String {
+        let shown = self
+            .names
+            .iter()
+            .rev()
+            .find(|(i, _)| *i == var)
+            .map_or(var, |(_, shown)| *shown);
+        var_name(shown)
+    }
+
+    fn level(token: &Token) -> u8 {
+        match token {
+            Token::Int(i) if i.sign() == num_bigint::Sign::Minus => UNARY,
+            Token::Bool(_) | Token::Int(_) | Token::String(_) | Token::Id(_) => ATOM,
+            Token::UnaryOp(..) => UNARY,
+            Token::BinaryOp(op, _, _) => binary_info(op).map_or(APP, |(_, prec, _)| prec),
+            Token::If(..) | Token::Abstraction(..) => LOWEST,
+            Token::Application(..) => {
+                if token.fix_function().is_some() || let_bindings(token).is_some() {
+                    LOWEST
+                } else {
+                    APP
+                }
+            }
+        }
+    }
+
+    fn flat_if_fits(&mut self, token: &Token, prec: u8, indent: usize) -> Option<String> {
+        if !is_small(token) {
+            return None;
+        }
+        let flat = self.render(token, prec, indent, false);
+        (indent + flat.len() <= WIDTH).then_some(flat)
+    }
+
+    // The first line of the result starts at column `indent`, the following
+    // ones are already indented. With `multiline == false` everything is
+    // rendered on a single line.
+    fn render(&mut self, token: &Token, prec: u8, indent: usize, multiline: bool) -> String {
+        if Self::level(token) < prec {
+            return format!("({})", self.render(token, LOWEST, indent + 1, multiline));
+        }
+        if multiline {
+            if let Some(flat) = self.flat_if_fits(token, prec, indent) {
+                return flat;
+            }
+        }
+        match token {
+            Token::Bool(b) => b.to_string(),
+            Token::Int(i) => i.to_string(),
+            Token::String(s) => escape_string(s),
+            Token::Id(ELIDED) => "...".to_string(),
+            Token::Id(i) => self.name(*i),
+            Token::UnaryOp(op, inner) => {
+                let (name, prec) = match op {
+                    UnaryOp::NegInteger => ("-", APP),
+                    UnaryOp::Not => ("!", APP),
+                    UnaryOp::StringToInt => ("str_to_int ", ATOM),
+                    UnaryOp::IntToString => ("int_to_str ", ATOM),
+                };
+                let inner = self.render(inner, prec, indent + name.len(), multiline);
+                format!("{}{}", name, inner)
+            }
+            Token::BinaryOp(..) => self.render_binary(token, indent, multiline),
+            Token::If(..) => self.render_if(token, indent, multiline),
+            Token::Abstraction(..) => {
+                let (params, body) = params(token);
+                let len = self.names.len();
+                self.names.extend(params.iter().map(|&i| (i, i)));
+                let body = self.render(body, LOWEST, indent + 2, multiline);
+                self.names.truncate(len);
+                format!(
+                    "{} ->{}{}",
+                    header("fun", &params),
+                    newline(indent + 2, multiline),
+                    body
+                )
+            }
+            Token::Application(..) if Self::level(token) == LOWEST => {
+                self.render_let(token, indent, multiline)
+            }
+            Token::Application(lhs, rhs) => {
+                let mut args = vec![rhs];
+                let mut head = lhs;
+                while let Token::Application(lhs, rhs) = head.as_ref() {
+                    if Self::level(head) != APP {
+                        break;
+                    }
+                    args.push(rhs);
+                    head = lhs;
+                }
+                let mut res = self.render(head, ATOM, indent, multiline);
+                for arg in args.into_iter().rev() {
+                    res.push(' ');
+                    res.push_str(&self.render(arg, ATOM, indent + 2, multiline));
+                }
+                res
+            }
+        }
+    }
+
+    // Chains of the same precedence are collected iteratively, long `&&`
+    // chains in the efficiency tasks are hundreds of operands deep.
+    fn render_binary(&mut self, token: &Token, indent: usize, multiline: bool) -> String {
+        let Token::BinaryOp(op, first, second) = token else {
+            unreachable!()
+        };
+        let Some((symbol, prec, assoc)) = binary_info(op) else {
+            let name = if *op == BinaryOp::Prefix {
+                "take"
+            } else {
+                "drop"
+            };
+            let first = self.render(first, ATOM, indent, multiline);
+            let second = self.render(second, ATOM, indent, multiline);
+            return format!("{} {} {}", name, first, second);
+        };
+        // operands with the operator in front of them, the first one has none
+        let mut operands: Vec<(&str, &Token, u8)> = vec![];
+        match assoc {
+            Assoc::Left => {
+                let mut cur = token;
+                while let Token::BinaryOp(op, first, second) = cur {
+                    match binary_info(op) {
+                        Some((symbol, op_prec, _)) if op_prec == prec => {
+                            operands.push((symbol, second, prec + 1));
+                            cur = first;
+                        }
+                        _ => break,
+                    }
+                }
+                operands.push(("", cur, prec));
+                operands.reverse();
+            }
+            Assoc::Associative => {
+                // either grouping means the same, so nested chains on both
+                // sides are flattened
+                let mut stack = vec![token];
+                while let Some(cur) = stack.pop() {
+                    match cur {
+                        Token::BinaryOp(cur_op, first, second) if cur_op == op => {
+                            stack.push(second);
+                            stack.push(first);
+                        }
+                        _ => operands.push((symbol, cur, prec + 1)),
+                    }
+                }
+                operands[0].0 = "";
+            }
+            Assoc::None => {
+                operands.push(("", first, prec + 1));
+                operands.push((symbol, second, prec + 1));
+            }
+        }
+        let mut res = String::new();
+        for (symbol, operand, prec) in operands {
+            if !symbol.is_empty() {
+                res.push_str(&newline(indent, multiline));
+                res.push_str(symbol);
+                res.push(' ');
+            }
+            let operand_indent = indent + symbol.len() + 1;
+            res.push_str(&self.render(operand, prec, operand_indent, multiline));
+        }
+        res
+    }
+
+    fn render_if(&mut self, token: &Token, indent: usize, multiline: bool) -> String {
+        let mut res = String::new();
+        let mut cur = token;
+        while let Token::If(cond, first, second) = cur {
+            let cond = self.render(cond, LOWEST, indent + 3, multiline);
+            let first = self.render(first, LOWEST, indent + 2, multiline);
+            res.push_str(&format!(
+                "if {} then{}{}{}else",
+                cond,
+                newline(indent + 2, multiline),
+                first,
+                newline(indent, multiline),
+            ));
+            if !matches!(second.as_ref(), Token::If(..)) {
+                res.push_str(&newline(indent + 2, multiline));
+                res.push_str(&self.render(second, LOWEST, indent + 2, multiline));
+                break;
+            }
+            res.push(' ');
+            if multiline {
+                if let Some(flat) = self.flat_if_fits(second, LOWEST, indent + 5) {
+                    res.push_str(&flat);
+                    break;
+                }
+            }
+            cur = second;
+        }
+        res
+    }
+
+    // Consecutive `let`s are rendered one per line without nesting.
+    fn render_let(&mut self, token: &Token, indent: usize, multiline: bool) -> String {
+        let len = self.names.len();
+        let mut res = String::new();
+        let mut cur = token;
+        loop {
+            if let Some(Token::Abstraction(name, inner)) = cur.fix_function().map(|f| f.as_ref()) {
+                res.push_str(&self.let_rec(*name, *name, inner, indent, multiline));
+                res.push_str(&format!(
+                    "{}in {}",
+                    newline(indent, multiline),
+                    var_name(*name)
+                ));
+                break;
+            }
+            let Some((bindings, rest)) = let_bindings(cur) else {
+                res.push_str(&self.render(cur, LOWEST, indent, multiline));
+                break;
+            };
+            for (var, value) in bindings {
+                res.push_str(&self.let_binding(var, value, indent, multiline));
+                res.push_str(" in");
+                res.push_str(&newline(indent, multiline));
+                self.names.push((var, var));
+            }
+            cur = rest;
+        }
+        self.names.truncate(len);
+        res
+    }
+
+    fn let_binding(&mut self, var: usize, value: &Token, indent: usize, multiline: bool) -> String {
+        match value.fix_function().map(|f| f.as_ref()) {
+            // the recursive calls can use `var` directly unless it clashes
+            // with some variable of the function
+            Some(Token::Abstraction(self_var, inner))
+                if *self_var == var || !uses_var(inner, var) =>
+            {
+                self.let_rec(var, *self_var, inner, indent, multiline)
+            }
+            _ => {
+                let (params, body) = params(value);
+                let vars: Vec<_> = std::iter::once(var).chain(params.clone()).collect();
+                let len = self.names.len();
+                self.names.extend(params.iter().map(|&i| (i, i)));
+                let res = self.binding(&header("let", &vars), body, indent, multiline);
+                self.names.truncate(len);
+                res
+            }
+        }
+    }
+
+    // `let rec name params = body`, `self_var` is the variable the fixpoint
+    // function uses for the recursive calls.
+    fn let_rec(
+        &mut self,
+        name: usize,
+        self_var: usize,
+        func: &Token,
+        indent: usize,
+        multiline: bool,
+    ) -> String {
+        let (params, body) = params(func);
+        let vars: Vec<_> = std::iter::once(name).chain(params.clone()).collect();
+        let len = self.names.len();
+        self.names.push((self_var, name));
+        self.names.extend(params.iter().map(|&i| (i, i)));
+        let res = self.binding(&header("let rec", &vars), body, indent, multiline);
+        self.names.truncate(len);
+        res
+    }
+
+    fn binding(&mut self, header: &str, value: &Token, indent: usize, multiline: bool) -> String {
+        if !multiline {
+            return format!("{} = {}", header, self.render(value, LOWEST, indent, false));
+        }
+        // leave room for the " in" after the value
+        if let Some(flat) = self.flat_if_fits(value, LOWEST, indent + header.len() + 6) {
+            return format!("{} = {}", header, flat);
+        }
+        let value = self.render(value, LOWEST, indent + 2, multiline);
+        format!("{} ={}{}", header, newline(indent + 2, multiline), value)
+    }
+}
+
+#[test]
+fn decompile_simple() {
+    use crate::parser::parse_string;
+
+    let res = decompile(&parse_string("B$ B$ L# L$ v# B. SB%,,/ S}Q/2,$_ IK"));
+    assert_eq!(res, r#"let v2 = "Hello" ++ " World!" in let v3 = 42 in v2"#);
+
+    let res = decompile(&parse_string(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+    ));
+    assert_eq!(
+        res,
+        "(let rec v1 v2 = if v2 == 1 then 1 else v2 * v1 (v2 - 1) in v1) 5"
+    );
+
+    let res = decompile(&parse_string(
+        r#"? B< U- I$ B* I# B+ I" I" BT I# BD I! S4%34 U$ I!"#,
+    ));
+    assert_eq!(
+        res,
+        r#"if -3 < 2 * (1 + 1) then take 2 (drop 0 "test") else int_to_str 0"#
+    );
+
+    let res = decompile(&parse_string(r#"B& B- B- I# B- I" I! I! B& B& T F T"#));
+    assert_eq!(res, "2 - (1 - 0) - 0 && true && false && true");
+}
+
+#[test]
+fn decompile_efficiency() {
+    use crate::parser::parse_string;
+
+    for i in 1..=13 {
+        let input =
+            std::fs::read_to_string(format!("../efficiency/efficiency{:02}.in", i)).unwrap();
+        let res = decompile(&parse_string(&input));
+        eprintln!("efficiency{:02}:\n{}\n", i, res);
+        assert!(res.lines().all(|line| !line.trim().is_empty()));
+    }
+}
+
+#[test]
+fn decompile_truncated_deep() {
+    use crate::parser::{drop_deep, parse_string};
+
+    // only the first nodes are visited, rendering all of them would overflow
+    let deep = parse_string(&("B+ I\" ".repeat(100_000) + "I!"));
+    assert_eq!(decompile_truncated(&deep, 12), "1 + (1 + (1 ...");
+    drop_deep(deep);
+
+    let fact = parse_string(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+    );
+    assert_eq!(decompile_truncated(&fact, 100), decompile_line(&fact));
+}
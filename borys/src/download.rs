@@ -0,0 +1,115 @@
+use std::{fs, path::PathBuf};
+
+use crate::{
+    parser::{try_parse_string, EvalContext, Strategy, Token, BETA_REDUCTIONS_LIMIT},
+    protocol::{Client, ProtocolError, TIME_LIMIT},
+    scores::{parse_task_scores, save_page},
+    workspace::{Workspace, FAMILIES},
+};
+
+// Fetches the tasks of a family and writes them where the solvers read them,
+// e.g. `<root>/spaceship/spaceship01.in`. Responses that are too costly to
+// evaluate are stored as the program in `spaceship01.icfp` instead.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Downloaded {
+    Text(PathBuf),
+    Program(PathBuf),
+}
+
+/// Downloads the tasks of `family` with the given ids, or all the tasks the
+/// server lists for it.
+pub async fn download_family(
+    client: &Client,
+    ws: &Workspace,
+    family: &str,
+    ids: Option<Vec<usize>>,
+) -> Result<Vec<Downloaded>, ProtocolError> {
+    if !FAMILIES.contains(&family) {
+        return Err(ProtocolError::InvalidRequest(format!("get {}", family)));
+    }
+    let ids = match ids {
+        Some(ids) => ids,
+        None => {
+            let listing = match client.get(family).await? {
+                Token::String(text) => String::from_utf8_lossy(&text).into_owned(),
+                token => return Err(ProtocolError::Decode(format!("{:?}", token))),
+            };
+            save_page(ws, family, &listing)?;
+            parse_task_scores(&listing)
+                .iter()
+                .filter_map(|task| task.task.strip_prefix(family)?.parse().ok())
+                .collect()
+        }
+    };
+    fs::create_dir_all(ws.family_dir(family))?;
+    let mut res = vec![];
+    for id in ids {
+        let body = client.send_raw(&format!("get {}{}", family, id)).await?;
+        let downloaded = match evaluate(&body)? {
+            Some(text) => {
+                let path = ws.input(family, id);
+                fs::write(&path, text)?;
+                Downloaded::Text(path)
+            }
+            None => {
+                let path = ws.program(family, id);
+                fs::write(&path, body)?;
+                Downloaded::Program(path)
+            }
+        };
+        eprintln!("{}{}: {:?}", family, id, downloaded);
+        res.push(downloaded);
+    }
+    Ok(res)
+}
+
+// The text of a response, `None` when evaluating it exceeds the limits.
+fn evaluate(body: &str) -> Result<Option<Vec<u8>>, ProtocolError> {
+    let parsed = try_parse_string(body).map_err(|err| ProtocolError::Decode(err.to_string()))?;
+    let value = EvalContext::new(Strategy::Need)
+        .with_beta_limit(BETA_REDUCTIONS_LIMIT)
+        .with_time_limit(TIME_LIMIT)
+        .eval(&parsed);
+    match value {
+        Ok(Token::String(text)) => Ok(Some(text)),
+        Ok(token) => Err(ProtocolError::Decode(format!("{:?}", token))),
+        Err(_) => Ok(None),
+    }
+}
+
+#[tokio::test]
+async fn download() {
+    use crate::mock_server::MockServer;
+
+    let url = MockServer::new(Workspace::default()).spawn().await.unwrap();
+    let client = Client::new(&url, "");
+    let root = std::env::temp_dir().join(format!("download_{}", std::process::id()));
+    let ws = Workspace::new(&root);
+
+    let res = download_family(&client, &ws, "spaceship", None)
+        .await
+        .unwrap();
+    assert_eq!(res.len(), 25);
+    for (i, downloaded) in res.iter().enumerate() {
+        let path = ws.input("spaceship", i + 1);
+        assert_eq!(downloaded, &Downloaded::Text(path.clone()));
+        let expected = Workspace::default().input("spaceship", i + 1);
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            fs::read_to_string(expected).unwrap()
+        );
+    }
+
+    let res = download_family(&client, &ws, "lambdaman", Some(vec![4]))
+        .await
+        .unwrap();
+    assert_eq!(
+        res,
+        [Downloaded::Text(root.join("lambdaman/lambdaman04.in"))]
+    );
+    fs::remove_dir_all(&root).unwrap();
+
+    // a response that loops is kept as the program
+    assert_eq!(evaluate(r#"B$ L" B$ v" v" L" B$ v" v""#).unwrap(), None);
+}
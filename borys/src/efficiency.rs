@@ -0,0 +1,446 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    rc::Rc,
+};
+
+use num_bigint::BigInt;
+
+use crate::{
+    decompiler::{decompile, decompile_arg, params, var_name},
+    parser::{children, BinaryOp, Token, UnaryOp},
+};
+
+// Summarizes the recursive functions of the efficiency programs, which are too
+// slow to evaluate and have to be solved by recognizing what they compute.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// A single recursive call moving `param` by `step`.
+    RangeLoop { param: usize, step: BigInt },
+    /// A loop over divisors `param` of `number` returning false on the first
+    /// one that divides it.
+    PrimalityTest { param: usize, number: String },
+    /// Recursive calls halving `param`.
+    BitDecomposition { param: usize },
+    /// Recursive calls on `param - 1` and `param - 2`.
+    FibonacciLike { param: usize },
+}
+
+/// `var / base^i % base` is extracted for `count` different `i`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digits {
+    pub var: usize,
+    pub base: BigInt,
+    pub count: usize,
+}
+
+/// A result of the function together with the conditions leading to it,
+/// `None` when it is returned unconditionally.
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub condition: Option<String>,
+    pub result: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecursiveFunction {
+    /// The variable the function is bound to, or its self variable.
+    pub name: usize,
+    pub params: Vec<usize>,
+    /// Arguments when the fixpoint is applied right away.
+    pub initial_args: Vec<String>,
+    pub base_cases: Vec<Branch>,
+    pub recursive_calls: Vec<Vec<String>>,
+    pub patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub functions: Vec<RecursiveFunction>,
+    pub digits: Vec<Digits>,
+}
+
+pub fn analyze(token: &Token) -> Analysis {
+    let mut functions = vec![];
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        let mut args = vec![];
+        let mut head = token;
+        while let Token::Application(lhs, rhs) = head {
+            if head.fix_function().is_some() {
+                break;
+            }
+            args.push(rhs.as_ref());
+            head = lhs;
+        }
+        args.reverse();
+        if let Some(f) = head.fix_function() {
+            let initial_args = args.iter().map(|arg| decompile_arg(arg)).collect();
+            functions.extend(analyze_function(f, None, initial_args));
+            stack.push(f);
+            stack.extend(args);
+            continue;
+        }
+        if let Token::Application(lhs, value) = token {
+            if let (Token::Abstraction(var, body), Some(f)) = (lhs.as_ref(), value.fix_function()) {
+                functions.extend(analyze_function(f, Some(*var), vec![]));
+                stack.push(f);
+                stack.push(body);
+                continue;
+            }
+        }
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    Analysis {
+        functions,
+        digits: find_digits(token),
+    }
+}
+
+// Whether `var` is free in `token`.
+fn mentions(token: &Token, var: usize) -> bool {
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        match token {
+            Token::Id(i) if *i == var => return true,
+            Token::Abstraction(i, _) if *i == var => {}
+            _ => stack.extend(children(token).into_iter().map(|c| c.as_ref())),
+        }
+    }
+    false
+}
+
+// Arguments of the calls `f a b ...` with `f` free in `token`.
+fn calls_of(token: &Token, f: usize) -> Vec<Vec<&Token>> {
+    let mut res = vec![];
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        match token {
+            Token::Abstraction(i, _) if *i == f => continue,
+            Token::Application(..) => {
+                let mut args = vec![];
+                let mut head = token;
+                while let Token::Application(lhs, rhs) = head {
+                    args.push(rhs.as_ref());
+                    head = lhs;
+                }
+                args.reverse();
+                if *head == Token::Id(f) {
+                    stack.extend(args.iter().copied());
+                    res.push(args);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    res
+}
+
+// The leaves of the `if` tree of the body with the conditions leading to them,
+// looking through `let`s.
+fn branches(body: &Rc<Token>) -> Vec<(Vec<Rc<Token>>, &Rc<Token>)> {
+    let mut res = vec![];
+    let mut stack = vec![(vec![], body)];
+    while let Some((conditions, token)) = stack.pop() {
+        if let Token::Application(lhs, _) = token.as_ref() {
+            if let Token::Abstraction(_, body) = lhs.as_ref() {
+                stack.push((conditions, body));
+                continue;
+            }
+        }
+        let Token::If(cond, first, second) = token.as_ref() else {
+            res.push((conditions, token));
+            continue;
+        };
+        let mut negated = conditions.clone();
+        negated.push(Rc::new(Token::UnaryOp(UnaryOp::Not, cond.clone())));
+        stack.push((negated, second));
+        let mut conditions = conditions;
+        conditions.push(cond.clone());
+        stack.push((conditions, first));
+    }
+    res
+}
+
+// `p + c` or `p - c` as `c` or `-c`.
+fn step(arg: &Token, param: usize) -> Option<BigInt> {
+    match arg {
+        Token::BinaryOp(BinaryOp::Add, a, b) => match (a.as_ref(), b.as_ref()) {
+            (Token::Id(p), Token::Int(c)) | (Token::Int(c), Token::Id(p)) if *p == param => {
+                Some(c.clone())
+            }
+            _ => None,
+        },
+        Token::BinaryOp(BinaryOp::Sub, a, b) => match (a.as_ref(), b.as_ref()) {
+            (Token::Id(p), Token::Int(c)) if *p == param => Some(-c),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn halves(arg: &Token, param: usize) -> bool {
+    match arg {
+        Token::BinaryOp(BinaryOp::Div, a, b) => {
+            **a == Token::Id(param) && **b == Token::Int(2.into())
+        }
+        _ => false,
+    }
+}
+
+// `number % param == 0` somewhere in `token`.
+fn divisibility_check(token: &Token, param: usize) -> Option<&Token> {
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        if let Token::BinaryOp(BinaryOp::Eq, a, b) = token {
+            if let (Token::BinaryOp(BinaryOp::Mod, number, p), Token::Int(zero)) =
+                (a.as_ref(), b.as_ref())
+            {
+                if **p == Token::Id(param) && *zero == BigInt::ZERO {
+                    return Some(number);
+                }
+            }
+        }
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    None
+}
+
+fn analyze_function(
+    f: &Rc<Token>,
+    binding: Option<usize>,
+    initial_args: Vec<String>,
+) -> Option<RecursiveFunction> {
+    let Token::Abstraction(self_var, inner) = f.as_ref() else {
+        return None;
+    };
+    let (params, _) = params(inner);
+    // the body as an `Rc` for `branches`
+    let mut body = inner;
+    while let Token::Abstraction(_, inner) = body.as_ref() {
+        body = inner;
+    }
+    let mut base_cases = vec![];
+    let mut returns_false = false;
+    for (conditions, result) in branches(body) {
+        if mentions(result, *self_var) {
+            continue;
+        }
+        returns_false |= **result == Token::Bool(false);
+        let condition = conditions
+            .into_iter()
+            .reduce(|a, b| Rc::new(Token::BinaryOp(BinaryOp::And, a, b)))
+            .map(|c| decompile(&c));
+        base_cases.push(Branch {
+            condition,
+            result: decompile(result),
+        });
+    }
+
+    let calls = calls_of(body, *self_var);
+    let mut patterns = vec![];
+    for (i, &param) in params.iter().enumerate() {
+        let steps: Vec<_> = calls
+            .iter()
+            .filter_map(|args| args.get(i).and_then(|arg| step(arg, param)))
+            .collect();
+        if steps.contains(&BigInt::from(-1)) && steps.contains(&BigInt::from(-2)) {
+            patterns.push(Pattern::FibonacciLike { param });
+        } else if calls.len() == 1 && steps.len() == 1 {
+            let step = steps[0].clone();
+            if step == BigInt::from(1) && returns_false {
+                if let Some(number) = divisibility_check(body, param) {
+                    patterns.push(Pattern::PrimalityTest {
+                        param,
+                        number: decompile(number),
+                    });
+                }
+            }
+            patterns.push(Pattern::RangeLoop { param, step });
+        }
+        if calls
+            .iter()
+            .any(|args| args.get(i).is_some_and(|arg| halves(arg, param)))
+        {
+            patterns.push(Pattern::BitDecomposition { param });
+        }
+    }
+
+    let recursive_calls = calls
+        .iter()
+        .map(|args| args.iter().map(|arg| decompile_arg(arg)).collect())
+        .collect();
+    Some(RecursiveFunction {
+        name: binding.unwrap_or(*self_var),
+        params,
+        initial_args,
+        base_cases,
+        recursive_calls,
+        patterns,
+    })
+}
+
+fn power_of(mut n: BigInt, base: &BigInt) -> bool {
+    while n > BigInt::from(1) && (&n % base) == BigInt::ZERO {
+        n /= base;
+    }
+    n == BigInt::from(1)
+}
+
+// `var / c % base` with `c` a power of `base`, as in the SAT and sudoku checks.
+fn find_digits(token: &Token) -> Vec<Digits> {
+    let mut found: BTreeMap<(usize, BigInt), BTreeSet<BigInt>> = BTreeMap::new();
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        if let Token::BinaryOp(BinaryOp::Mod, a, base) = token {
+            if let (Token::BinaryOp(BinaryOp::Div, var, c), Token::Int(base)) =
+                (a.as_ref(), base.as_ref())
+            {
+                if let (Token::Id(var), Token::Int(c)) = (var.as_ref(), c.as_ref()) {
+                    if *base > BigInt::from(1) && power_of(c.clone(), base) {
+                        found
+                            .entry((*var, base.clone()))
+                            .or_default()
+                            .insert(c.clone());
+                    }
+                }
+            }
+        }
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    found
+        .into_iter()
+        .filter(|(_, powers)| powers.len() > 1)
+        .map(|((var, base), powers)| Digits {
+            var,
+            base,
+            count: powers.len(),
+        })
+        .collect()
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::RangeLoop { param, step } => {
+                write!(f, "loop over {} with step {}", var_name(*param), step)
+            }
+            Pattern::PrimalityTest { param, number } => write!(
+                f,
+                "primality test of {} by trial division with {}",
+                number,
+                var_name(*param)
+            ),
+            Pattern::BitDecomposition { param } => {
+                write!(f, "bit decomposition of {}", var_name(*param))
+            }
+            Pattern::FibonacciLike { param } => {
+                write!(f, "fibonacci-like recurrence on {}", var_name(*param))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for function in &self.functions {
+            let params: Vec<_> = function.params.iter().map(|&p| var_name(p)).collect();
+            write!(f, "{} {}", var_name(function.name), params.join(" "))?;
+            if !function.initial_args.is_empty() {
+                write!(f, " (called with {})", function.initial_args.join(" "))?;
+            }
+            writeln!(f, ":")?;
+            for case in &function.base_cases {
+                match &case.condition {
+                    Some(condition) => {
+                        writeln!(f, "  base case: {} -> {}", condition, case.result)?
+                    }
+                    None => writeln!(f, "  base case: {}", case.result)?,
+                }
+            }
+            for args in &function.recursive_calls {
+                writeln!(
+                    f,
+                    "  recursive call: {} {}",
+                    var_name(function.name),
+                    args.join(" ")
+                )?;
+            }
+            for pattern in &function.patterns {
+                writeln!(f, "  pattern: {}", pattern)?;
+            }
+        }
+        for digits in &self.digits {
+            writeln!(
+                f,
+                "{} base-{} digits of {}",
+                digits.count,
+                digits.base,
+                var_name(digits.var)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn analyze_efficiency() {
+    use crate::parser::parse_string;
+
+    let analyses: Vec<_> = (1..=13)
+        .map(|i| {
+            let input =
+                std::fs::read_to_string(format!("../efficiency/efficiency{:02}.in", i)).unwrap();
+            let analysis = analyze(&parse_string(&input));
+            eprintln!("efficiency{:02}:\n{}", i, analysis);
+            analysis
+        })
+        .collect();
+
+    let countdown = &analyses[1].functions[0];
+    assert_eq!(countdown.initial_args, ["9345873499"]);
+    assert_eq!(
+        countdown.base_cases[0].condition.as_deref(),
+        Some("v4 == 0")
+    );
+    assert_eq!(countdown.base_cases[0].result, "1");
+    assert_eq!(countdown.recursive_calls, [["(v4 - 1)"]]);
+    assert_eq!(
+        countdown.patterns,
+        [Pattern::RangeLoop {
+            param: 4,
+            step: BigInt::from(-1)
+        }]
+    );
+
+    assert_eq!(
+        analyses[3].functions[0].patterns,
+        [Pattern::FibonacciLike { param: 4 }]
+    );
+
+    let patterns: Vec<_> = analyses[4]
+        .functions
+        .iter()
+        .flat_map(|f| f.patterns.clone())
+        .collect();
+    assert!(patterns.contains(&Pattern::PrimalityTest {
+        param: 4,
+        number: "v5".to_string()
+    }));
+    assert!(patterns.contains(&Pattern::BitDecomposition { param: 4 }));
+
+    let digits = |i: usize| analyses[i].digits.clone();
+    assert_eq!(
+        digits(6),
+        [Digits {
+            var: 41,
+            base: 2.into(),
+            count: 40
+        }]
+    );
+    assert_eq!(digits(8)[0].base, 9.into());
+    assert_eq!(digits(8)[0].count, 81);
+}
@@ -7,24 +7,50 @@ use std::{
     time::Instant,
 };
 
+use num_bigint::BigInt;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 use crate::{
     array_2d::Array2D,
+    parser::{encode_integer, encode_string},
     simulated_annealing::{SearchFor, SimulatedAnnealing},
-    TEST_ID,
+    workspace::Workspace,
 };
 
+// the order of the digits in `compress`
+const COMPRESSED_MOVES: &str = "DRUL";
+
+/// A `solve lambdaman<id>` request that expands the moves from a base 4
+/// number, the first move being the lowest digit and a leading 1 marking the
+/// end. `None` if a move is not one of `LDRU`.
+pub fn compress(test_id: usize, moves: &str) -> Option<String> {
+    let mut n = BigInt::from(1);
+    for c in moves.chars().rev() {
+        n = n * 4 + COMPRESSED_MOVES.find(c)?;
+    }
+    let mut digits = vec![b'S'];
+    encode_integer(&n, &mut digits);
+    Some(format!(
+        "B. {} B$ B$ L\" B$ L# B$ v\" B$ v# v# L# B$ v\" B$ v# v# L\" L# ? B= v# I\" S B. BT I\" BD B% v# I% {} B$ v\" B/ v# I% U# {}",
+        encode_string(&format!("solve lambdaman{} ", test_id)),
+        encode_string(COMPRESSED_MOVES),
+        String::from_utf8(digits).unwrap()
+    ))
+}
+
 pub struct Task {
     pub walls: Array2D<bool>,
     pub start: (usize, usize),
 }
 
-pub fn read_task(test_id: usize) -> Task {
-    let input = std::fs::read_to_string(format!("../lambdaman/lambdaman{test_id:02}.in")).unwrap();
+pub fn read_task(ws: &Workspace, test_id: usize) -> Task {
+    let input = std::fs::read_to_string(ws.input("lambdaman", test_id)).unwrap();
+    parse_task(&input)
+}
 
+pub(crate) fn parse_task(input: &str) -> Task {
     let lines = input
         .lines()
         .filter(|x| !x.trim().is_empty())
@@ -48,10 +74,8 @@ pub fn read_task(test_id: usize) -> Task {
     Task { walls, start }
 }
 
-pub fn lambda_solver() {
-    let test_id = TEST_ID;
-
-    let Task { walls, start } = read_task(test_id);
+pub fn lambda_solver(ws: &Workspace, test_id: usize) {
+    let Task { walls, start } = read_task(ws, test_id);
     let n = walls.len();
     let mut to_visit = 0;
     for i in 0..n {
@@ -89,6 +113,40 @@ pub fn lambda_solver() {
 
 const DX: [isize; 4] = [0, 1, 0, -1];
 const DY: [isize; 4] = [-1, 0, 1, 0];
+// the moves in the order of `DX` and `DY`
+const MOVES: &[u8; 4] = b"LDRU";
+
+/// The number of cells the moves leave unvisited, `None` if a move is not one
+/// of `LDRU`.
+pub(crate) fn check_moves(task: &Task, moves: &str) -> Option<usize> {
+    let walls = &task.walls;
+    let n = walls.len();
+    let m = walls[0].len();
+    let mut seen = Array2D::new(false, n, m);
+    seen[task.start.0][task.start.1] = true;
+    let mut cur_pos = task.start;
+    for c in moves.bytes() {
+        let dir = MOVES.iter().position(|&m| m == c)?;
+        let next_pos = (
+            cur_pos.0.overflowing_add_signed(DX[dir]).0,
+            cur_pos.1.overflowing_add_signed(DY[dir]).0,
+        );
+        if next_pos.0 >= n || next_pos.1 >= m || walls[next_pos.0][next_pos.1] {
+            continue;
+        }
+        cur_pos = next_pos;
+        seen[cur_pos.0][cur_pos.1] = true;
+    }
+    let mut unvisited = 0;
+    for i in 0..n {
+        for j in 0..m {
+            if !walls[i][j] && !seen[i][j] {
+                unvisited += 1;
+            }
+        }
+    }
+    Some(unvisited)
+}
 
 pub const DEFAULT_STEPS_LIMIT: usize = 1_000_000;
 
@@ -273,3 +331,31 @@ fn check(
     }
     to_visit
 }
+
+#[test]
+fn compress_moves() {
+    use crate::{
+        parser::{eval_with, parse_string, Strategy, Token},
+        workspace::Source,
+    };
+
+    let moves = "UDLLLDURRRRRURR";
+    let program = compress(1, moves).unwrap();
+    let expected = format!("solve lambdaman1 {}", moves);
+    let res = eval_with(&parse_string(&program), Strategy::Need);
+    assert_eq!(res, Token::String(expected.into_bytes()));
+    let ws = Workspace::default();
+    assert_eq!(check_moves(&read_task(&ws, 1), moves), Some(0));
+
+    // the same as the program test.cpp wrote
+    let saved = ws.find_solution("lambdaman", 5, Source::Team).unwrap();
+    let saved = std::fs::read_to_string(saved).unwrap();
+    let saved = saved.lines().last().unwrap();
+    let Token::String(request) = eval_with(&parse_string(saved), Strategy::Need) else {
+        panic!("not a string");
+    };
+    let request = String::from_utf8(request).unwrap();
+    let moves = request.strip_prefix("solve lambdaman5 ").unwrap();
+    assert_eq!(compress(5, moves).unwrap(), saved);
+    assert_eq!(compress(5, "X"), None);
+}
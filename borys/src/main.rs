@@ -1,57 +1,265 @@
 use std::fs;
 
 pub mod array_2d;
+mod cache;
+mod cli;
+mod compiler;
+mod decompiler;
+mod download;
+mod efficiency;
 mod lambdaman;
 pub mod local_solver;
+mod mock_server;
 mod parser;
 mod protocol;
+mod repl;
+mod scores;
+mod simplifier;
 pub mod simulated_annealing;
 pub mod spaceship;
+mod term;
+mod trace;
 pub mod tsp;
+mod types;
 mod viz;
 mod viz_lambda;
+mod workspace;
 pub mod zoomer;
 
-pub const TEST_ID: usize = 11;
+use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use crate::{
-    parser::{encode_string, eval, parse_string},
-    protocol::send_msg,
-    spaceship::spaceship_solve,
+    cli::{Command, EvalOptions, LambdamanAction, SpaceshipAction, USAGE},
+    parser::{
+        drop_deep, encode_string, encode_token, try_parse_string, EvalContext, Strategy,
+        BETA_REDUCTIONS_LIMIT,
+    },
+    trace::Tracer,
+    workspace::Workspace,
 };
 
-fn simple_converter() {
-    // read from stdin
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input).unwrap();
-    let parsed = parse_string(&input);
-    eprintln!("Parsed:\n{:?}\n\n", parsed);
-    let evaluated = eval(&parsed);
-    println!("{:?}", evaluated);
+// the file, or stdin for `None`
+fn read_input(path: &Option<PathBuf>) -> Result<String> {
+    Ok(match path {
+        Some(path) => fs::read_to_string(path)?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    })
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // if CONV env variable is set, run the converter
-    if dotenv::var("CONV").is_ok() {
-        simple_converter();
-        return Ok(());
+fn simple_converter(options: &EvalOptions) -> Result<()> {
+    let input = read_input(&options.input)?;
+    let mut parsed = try_parse_string(input.trim()).map_err(|err| anyhow!("{}", err))?;
+    if options.simplify {
+        parsed = simplifier::simplify(&parsed);
+        eprintln!(
+            "Simplified: {} -> {} bytes",
+            input.trim().len(),
+            encode_token(&parsed).len()
+        );
+    }
+    if options.decompile {
+        eprintln!("Decompiled:\n{}\n\n", decompiler::decompile(&parsed));
+    } else {
+        eprintln!("Parsed:\n{:?}\n\n", parsed);
+    }
+    // untyped programs can still be fine, so this is only a warning
+    match types::infer(&parsed) {
+        Ok(t) => eprintln!("Type: {}", t),
+        Err(err) => eprintln!("Warning: {}", err),
+    }
+    let strategy = options.strategy;
+    let beta_limit = options.beta_limit.unwrap_or(BETA_REDUCTIONS_LIMIT);
+    let mut ctx = EvalContext::new(strategy).with_beta_limit(beta_limit);
+    if let Some(tracer) = tracer(options)? {
+        ctx = ctx.with_tracer(tracer);
+    }
+    let evaluated = ctx.eval(&parsed);
+    if let Some(tracer) = &ctx.tracer {
+        let mut tracer = tracer.borrow_mut();
+        if let Err(err) = tracer.finish() {
+            eprintln!("Failed to write the trace: {}", err);
+        }
+        eprintln!("Traced {} steps", tracer.step);
+    }
+    eprintln!(
+        "Beta reductions: {}/{}. Time: {:?}",
+        ctx.beta_reductions, beta_limit, ctx.elapsed
+    );
+    if strategy == Strategy::Memo {
+        eprintln!("Memo hits: {}/{}", ctx.memo_hits, ctx.memo_calls);
+    }
+    drop_deep(parsed);
+    println!("{:?}", evaluated.map_err(|err| anyhow!("{}", err))?);
+    Ok(())
+}
+
+// `--trace <file>` (or `-` for stderr) logs the reduction steps,
+// `--trace-depth` and `--trace-steps` limit which ones are written.
+fn tracer(options: &EvalOptions) -> Result<Option<Tracer>> {
+    let Some(path) = &options.trace else {
+        return Ok(None);
+    };
+    let mut tracer = if path == "-" {
+        Tracer::new(std::io::stderr())
+    } else {
+        Tracer::new(std::io::BufWriter::new(fs::File::create(path)?))
+    };
+    if let Some(depths) = options.trace_depth.clone() {
+        tracer = tracer.with_depths(depths);
+    }
+    if let Some(steps) = options.trace_steps.clone() {
+        tracer = tracer.with_steps(steps);
+    }
+    Ok(Some(tracer))
+}
+
+fn dsl_compiler(input: &Option<PathBuf>) -> Result<()> {
+    let source = read_input(input)?;
+    let token = compiler::compile(&source).map_err(|err| anyhow!("{}", err))?;
+    if let Err(err) = types::infer(&token) {
+        eprintln!("Warning: {}", err);
+    }
+    println!("{}", encode_token(&token));
+    Ok(())
+}
+
+fn efficiency_analysis(input: &Option<PathBuf>) -> Result<()> {
+    let input = read_input(input)?;
+    let parsed = try_parse_string(input.trim()).map_err(|err| anyhow!("{}", err))?;
+    println!("{}\n", decompiler::decompile(&parsed));
+    print!("{}", efficiency::analyze(&parsed));
+    Ok(())
+}
+
+async fn send(ws: &Workspace, msg: &str) -> Result<()> {
+    let request: protocol::Request = msg.parse()?;
+    let res = protocol::Client::from_env()?.send(&request).await?;
+    match res {
+        parser::Token::String(text) => {
+            let text = String::from_utf8_lossy(&text);
+            if let protocol::Request::Get(page) = &request {
+                scores::save_page(ws, page, &text)?;
+            }
+            println!("{}", text)
+        }
+        res => println!("{}", decompiler::decompile(&res)),
     }
-    if dotenv::var("SPACESHIP_DRAW").is_ok() {
-        spaceship::spaceship_draw();
+    Ok(())
+}
+
+// The queue is flushed by `spaceship submit` too, this retries what it left.
+async fn queue(ws: &Workspace, flush: bool) -> Result<()> {
+    let client = if flush {
+        protocol::Client::from_env()?
+    } else {
+        protocol::Client::new(protocol::BASE_URL, "")
+    };
+    let mut queue = protocol::SubmissionQueue::from_env(client, ws.queue_dir())?;
+    if !flush {
+        for request in queue.pending() {
+            println!("{}", request);
+        }
         return Ok(());
     }
-    println!("Hello, world!");
-    viz_lambda::viz_lambda_main().unwrap();
-    // if spaceship_solve().await {
-    //     return Ok(());
-    // }
+    let mut tasks: Vec<String> = queue
+        .pending()
+        .filter_map(|request| match request {
+            protocol::Request::Solve { task, .. } => Some(task.clone()),
+            _ => None,
+        })
+        .collect();
+    tasks.dedup();
+    queue = queue.with_scores(scores::ScoresDb::open(ws.scores())?);
+    let res = queue.flush().await;
+    for task in tasks {
+        println!("{}: {}", task, queue.verdict(&task).unwrap_or_default());
+    }
+    eprintln!("{} submissions pending", queue.pending().count());
+    Ok(res?)
+}
 
-    // test();
-    // send_msg("get spaceship").await?;
+async fn spaceship(ws: &Workspace, action: SpaceshipAction, id: usize) -> Result<()> {
+    match action {
+        SpaceshipAction::Solve => {
+            spaceship::spaceship_solve(ws, id).await;
+        }
+        SpaceshipAction::Draw => spaceship::spaceship_draw(ws, id),
+        SpaceshipAction::Viz => viz::viz_main(ws, id).map_err(|err| anyhow!("{}", err))?,
+        SpaceshipAction::Submit => {
+            spaceship::send_solution(ws, id, &spaceship::read_solution(ws, id)).await
+        }
+    }
+    Ok(())
+}
 
-    // lambdaman::lambda_solver();
+fn lambdaman(
+    ws: &Workspace,
+    action: LambdamanAction,
+    id: usize,
+    input: &Option<PathBuf>,
+) -> Result<()> {
+    match action {
+        LambdamanAction::Solve => lambdaman::lambda_solver(ws, id),
+        LambdamanAction::Viz => {
+            viz_lambda::viz_lambda_main(ws, id).map_err(|err| anyhow!("{}", err))?
+        }
+        LambdamanAction::Compress => {
+            let moves = read_input(input)?;
+            let program = lambdaman::compress(id, moves.trim())
+                .ok_or_else(|| anyhow!("Invalid moves, expected one of LDRU"))?;
+            let parsed = try_parse_string(&program).map_err(|err| anyhow!("{}", err))?;
+            types::check_message(&parsed).map_err(|err| anyhow!("{}", err))?;
+            println!("{}", program);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let ws = Workspace::from_env();
+    match command {
+        Command::Eval(options) => simple_converter(&options)?,
+        Command::Encode(text) => {
+            let text = match text {
+                Some(text) => text,
+                None => read_input(&None)?,
+            };
+            println!("{}", encode_string(&text));
+        }
+        Command::Compile(input) => dsl_compiler(&input)?,
+        Command::Analyze(input) => efficiency_analysis(&input)?,
+        Command::Repl => repl::repl().await?,
+        Command::Send(msg) => send(&ws, &msg).await?,
+        Command::Queue { flush } => queue(&ws, flush).await?,
+        Command::Download { family, ids } => {
+            let client = protocol::Client::from_env()?;
+            let ids = ids.map(|ids| ids.collect());
+            download::download_family(&client, &ws, &family, ids).await?;
+        }
+        Command::MockServer(addr) => {
+            let mut server = mock_server::MockServer::new(ws);
+            if let Ok(token) = dotenv::var("TOKEN") {
+                server = server.with_token(&token);
+            }
+            eprintln!("Mock server listening on http://{}", addr);
+            server
+                .run(tokio::net::TcpListener::bind(&addr).await?)
+                .await?;
+        }
+        Command::Spaceship(action, id) => spaceship(&ws, action, id).await?,
+        Command::Lambdaman { action, id, input } => lambdaman(&ws, action, id, &input)?,
+        Command::Help => println!("{}", USAGE),
+    }
     Ok(())
 }
@@ -0,0 +1,331 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    lambdaman,
+    parser::{
+        encode_string, try_parse_string, EvalContext, Strategy, Token, ALPH, BETA_REDUCTIONS_LIMIT,
+    },
+    protocol::Request,
+    spaceship,
+    workspace::{Workspace, FAMILIES},
+};
+
+// A local stand-in for the `communicate` endpoint. Requests are ICFP strings
+// as for the real server, `get <kind><n>` serves the task files from the data
+// directory and `solve` checks the answer with our own validators. The
+// responses are plain ICFP strings.
+
+/// Time given to evaluate a request, which also gets `BETA_REDUCTIONS_LIMIT`.
+const REQUEST_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Time given to evaluate an efficiency program when checking an answer.
+const EFFICIENCY_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct MockServer {
+    workspace: Workspace,
+    token: Option<String>,
+    // requests left to answer with 503
+    failures: AtomicUsize,
+}
+
+impl MockServer {
+    /// Serves the task inputs of the workspace.
+    pub fn new(workspace: Workspace) -> Self {
+        Self {
+            workspace,
+            token: None,
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Rejects requests without this bearer token.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Answers the first `n` requests with 503 Service Unavailable.
+    #[cfg(test)]
+    pub fn with_failures(self, n: usize) -> Self {
+        self.failures.store(n, Ordering::Relaxed);
+        self
+    }
+
+    pub async fn run(self, listener: TcpListener) -> std::io::Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.handle(stream).await {
+                    eprintln!("Mock server: {}", err);
+                }
+            });
+        }
+    }
+
+    /// Listens on a free local port, returns the base url.
+    #[cfg(test)]
+    pub async fn spawn(self) -> std::io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(self.run(listener));
+        Ok(url)
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(&mut stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let mut content_length = 0;
+        let mut authorization = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                    "authorization" => authorization = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await?;
+
+        let (status, response) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["POST", "/communicate", _] => {
+                let authorized = self.token.as_ref().is_none_or(|token| {
+                    authorization.as_deref() == Some(&format!("Bearer {}", token))
+                });
+                let failing = self
+                    .failures
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok();
+                if failing {
+                    (503, "Try again later".to_string())
+                } else if authorized {
+                    self.communicate(&String::from_utf8_lossy(&body))
+                } else {
+                    (401, "Unauthorized".to_string())
+                }
+            }
+            _ => (404, "Not found".to_string()),
+        };
+        eprintln!("Mock server: {} {}", request_line.trim(), status);
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            503 => "Service Unavailable",
+            _ => "Not Found",
+        };
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            reason,
+            response.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    /// Returns the status and the body of the response to an ICFP request.
+    pub fn communicate(&self, body: &str) -> (u16, String) {
+        let msg = match try_parse_string(body).and_then(|token| {
+            EvalContext::new(Strategy::Need)
+                .with_beta_limit(BETA_REDUCTIONS_LIMIT)
+                .with_time_limit(REQUEST_TIME_LIMIT)
+                .eval(&token)
+        }) {
+            Ok(Token::String(msg)) => String::from_utf8_lossy(&msg).into_owned(),
+            Ok(_) => return (400, "The request is not a string".to_string()),
+            Err(err) => return (400, err.to_string()),
+        };
+        match msg.parse::<Request>() {
+            Ok(request) => (200, encode_text(&self.respond(&request, body.len()))),
+            Err(err) => (400, err.to_string()),
+        }
+    }
+
+    fn respond(&self, request: &Request, size: usize) -> String {
+        match request {
+            Request::Echo(text) => text.clone(),
+            Request::Get(name) => match self.task(name) {
+                Some((_, input)) => input,
+                None if FAMILIES.contains(&name.as_str()) => self.list(name),
+                None => format!("Unknown task: {}", name),
+            },
+            Request::Solve { task, answer } => match self.task(task) {
+                Some((kind, input)) => self.check(kind, task, &input, answer, size),
+                None => format!("Unknown task: {}", task),
+            },
+        }
+    }
+
+    // `spaceship4` -> the kind and the input
+    fn task(&self, name: &str) -> Option<(&'static str, String)> {
+        let kind = FAMILIES
+            .into_iter()
+            .find(|kind| name.strip_prefix(kind).is_some_and(|n| !n.is_empty()))?;
+        let id: usize = name[kind.len()..].parse().ok()?;
+        let input = std::fs::read_to_string(self.workspace.input(kind, id)).ok()?;
+        Some((kind, input))
+    }
+
+    // in the format of the real server, without the scores
+    fn list(&self, kind: &str) -> String {
+        let tasks: String = (1..)
+            .map(|id| format!("{}{}", kind, id))
+            .take_while(|name| self.task(name).is_some())
+            .map(|name| format!("* [{}]\n", name))
+            .collect();
+        format!("The following tasks are available:\n{}", tasks)
+    }
+
+    fn check(&self, kind: &str, task: &str, input: &str, answer: &str, size: usize) -> String {
+        let correct =
+            |score: usize| format!("Correct, you solved {} with a score of {}!", task, score);
+        match kind {
+            "spaceship" => match spaceship::check_moves(&spaceship::parse_input(input), answer) {
+                Some(0) => correct(answer.len()),
+                Some(missed) => format!("Your solution missed {} squares", missed),
+                None => "Invalid moves, expected digits 1-9".to_string(),
+            },
+            "lambdaman" => match lambdaman::check_moves(&lambdaman::parse_task(input), answer) {
+                // as on the real server, the score is the size of the request
+                Some(0) => correct(size),
+                Some(missed) => format!("Your solution missed {} pills", missed),
+                None => "Invalid moves, expected one of LDRU".to_string(),
+            },
+            "efficiency" => {
+                let value = try_parse_string(input.trim()).and_then(|token| {
+                    EvalContext::new(Strategy::Need)
+                        .with_time_limit(EFFICIENCY_TIME_LIMIT)
+                        .eval(&token)
+                });
+                match value {
+                    Ok(Token::Int(n)) if n.to_string() == answer => correct(0),
+                    Ok(Token::Int(_)) => "Incorrect answer".to_string(),
+                    Ok(_) => format!("{} is not a number program", task),
+                    Err(err) => format!("Cannot check {}: {}", task, err),
+                }
+            }
+            _ => format!("{} solutions are not checked by the mock server", kind),
+        }
+    }
+}
+
+// `encode_string` for arbitrary text, characters missing from the alphabet are
+// replaced with `?`.
+fn encode_text(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .map(|c| if ALPH.contains(c) { c } else { '?' })
+        .collect();
+    encode_string(&text)
+}
+
+#[tokio::test]
+async fn mock_server() {
+    use crate::{
+        protocol::{decode, Client, ProtocolError},
+        workspace::Source,
+    };
+
+    let url = MockServer::new(Workspace::default())
+        .with_token("secret")
+        .spawn()
+        .await
+        .unwrap();
+    let client = Client::new(&url, "secret");
+    let string = |s: &str| Token::String(s.as_bytes().to_vec());
+
+    assert_eq!(
+        client.echo("Hello World!").await.unwrap(),
+        string("Hello World!")
+    );
+    let input = std::fs::read_to_string(Workspace::default().input("spaceship", 1)).unwrap();
+    assert_eq!(client.get("spaceship1").await.unwrap(), string(&input));
+    assert!(matches!(client.get("lambdaman").await.unwrap(),
+        Token::String(s) if s.starts_with(b"The following tasks are available:\n* [lambdaman1]\n* [lambdaman2]\n")));
+    assert_eq!(
+        client.get("spaceship0").await.unwrap(),
+        string("Unknown task: spaceship0")
+    );
+
+    assert_eq!(
+        client.solve("spaceship1", "31619").await.unwrap(),
+        string("Correct, you solved spaceship1 with a score of 5!")
+    );
+    assert_eq!(
+        client.solve("spaceship1", "3161").await.unwrap(),
+        string("Your solution missed 1 squares")
+    );
+    let res = client.solve("lambdaman1", "UDLLLDURRRRRURR").await.unwrap();
+    assert_eq!(
+        res,
+        string("Correct, you solved lambdaman1 with a score of 33!")
+    );
+    assert_eq!(
+        client.solve("lambdaman1", "RR").await.unwrap(),
+        string("Your solution missed 8 pills")
+    );
+    // a program that builds the request
+    let path = Workspace::default().find_solution("lambdaman", 1, Source::Team);
+    let program = std::fs::read_to_string(path.unwrap()).unwrap();
+    let program = program.lines().last().unwrap();
+    let res = decode(&client.send_icfp(program).await.unwrap()).unwrap();
+    assert_eq!(
+        res,
+        string(&format!(
+            "Correct, you solved lambdaman1 with a score of {}!",
+            program.len()
+        ))
+    );
+    assert_eq!(
+        client.solve("efficiency1", "1").await.unwrap(),
+        string("Incorrect answer")
+    );
+    assert_eq!(
+        client.solve("efficiency1", "17592186044416").await.unwrap(),
+        string("Correct, you solved efficiency1 with a score of 0!")
+    );
+
+    let res = Client::new(&url, "wrong").echo("hi").await;
+    assert!(matches!(res, Err(ProtocolError::Status { status, .. }) if status == 401));
+    // the client doesn't send programs that aren't strings
+    assert!(matches!(
+        client.send_icfp("I$").await,
+        Err(ProtocolError::Type(_))
+    ));
+    let res = reqwest::Client::new()
+        .post(format!("{}/communicate", url))
+        .bearer_auth("secret")
+        .body("I$")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 400);
+    assert_eq!(res.text().await.unwrap(), "The request is not a string");
+
+    let (status, body) =
+        MockServer::new(Workspace::default()).communicate("B$ L! B$ v! v! L! B$ v! v!");
+    assert_eq!(status, 400);
+    assert!(body.starts_with("Evaluation limit exceeded"));
+}
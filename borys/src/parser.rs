@@ -1,16 +1,24 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
     fmt::{self, Formatter},
     fs,
-    rc::Rc,
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
 };
 
 use num_bigint::{BigInt, Sign};
 
+use crate::{
+    decompiler::decompile_truncated,
+    term::{Arena, TermId},
+    trace::{StepKind, Tracer},
+};
+
 // https://www.minjiezha.com/tech/2011/01/19/A-Simple-Lambda-Calculus-Evaluator-III.html
 // https://laurenar.net/posts/lambda_calculus_interpreter/
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum UnaryOp {
     NegInteger,
     Not,
@@ -18,7 +26,7 @@ pub enum UnaryOp {
     IntToString,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum BinaryOp {
     Add,
     Sub,
@@ -37,7 +45,7 @@ pub enum BinaryOp {
 
 type Int = BigInt;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Token {
     Bool(bool),
     Int(Int),
@@ -51,6 +59,20 @@ pub enum Token {
 }
 
 impl Token {
+    pub(crate) fn head(&self) -> Head<'_> {
+        match self {
+            Token::Bool(b) => Head::Bool(*b),
+            Token::Int(i) => Head::Int(i),
+            Token::String(s) => Head::String(s),
+            Token::UnaryOp(op, _) => Head::UnaryOp(op),
+            Token::BinaryOp(op, _, _) => Head::BinaryOp(op),
+            Token::If(..) => Head::If,
+            Token::Application(..) => Head::Application,
+            Token::Abstraction(var, _) => Head::Abstraction(*var),
+            Token::Id(var) => Head::Id(*var),
+        }
+    }
+
     pub fn create_var(&self) -> (usize, Rc<Token>) {
         match self {
             Token::Abstraction(i, inner) => (*i, inner.clone()),
@@ -85,28 +107,106 @@ impl Token {
             _ => panic!("Expected String, got {:?}", self),
         }
     }
+
+    /// Recognises the Y combinator `\f. (\x. f (x x)) (\x. f (x x))` and its
+    /// eta-expanded form `\f. (\x. f (\y. x x y)) (\x. f (\y. x x y))`.
+    pub fn is_fix_combinator(&self) -> bool {
+        let Token::Abstraction(f, body) = self else {
+            return false;
+        };
+        let is_half = |token: &Token| {
+            let Token::Abstraction(x, inner) = token else {
+                return false;
+            };
+            let Token::Application(lhs, arg) = inner.as_ref() else {
+                return false;
+            };
+            let self_app = |token: &Token| match token {
+                Token::Application(a, b) => **a == Token::Id(*x) && **b == Token::Id(*x),
+                _ => false,
+            };
+            let arg_ok = match arg.as_ref() {
+                Token::Abstraction(y, inner) => match inner.as_ref() {
+                    Token::Application(a, b) => y != x && self_app(a) && **b == Token::Id(*y),
+                    _ => false,
+                },
+                arg => self_app(arg),
+            };
+            x != f && **lhs == Token::Id(*f) && arg_ok
+        };
+        match body.as_ref() {
+            Token::Application(a, b) => is_half(a) && is_half(b),
+            _ => false,
+        }
+    }
+
+    /// The Y combinator `\f. (\x. f (x x)) (\x. f (x x))` with the given
+    /// variables.
+    pub fn y_combinator(f: usize, x: usize) -> Token {
+        let half = Rc::new(Token::Abstraction(
+            x,
+            Rc::new(Token::Application(
+                Rc::new(Token::Id(f)),
+                Rc::new(Token::Application(
+                    Rc::new(Token::Id(x)),
+                    Rc::new(Token::Id(x)),
+                )),
+            )),
+        ));
+        Token::Abstraction(f, Rc::new(Token::Application(half.clone(), half)))
+    }
+
+    /// For a fixpoint `B$ Y F` returns `F`.
+    pub fn fix_function(&self) -> Option<&Rc<Token>> {
+        match self {
+            Token::Application(y, f) if y.is_fix_combinator() => Some(f),
+            _ => None,
+        }
+    }
 }
 
+// On an explicit stack, as programs can be deeper than the Rust stack.
 impl std::fmt::Debug for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Token::Bool(b) => write!(f, "Bool({})", b),
-            Token::Int(i) => write!(f, "Int({})", i),
-            Token::String(s) => {
-                let s = String::from_utf8(s.clone()).unwrap();
-                write!(f, "String({})", s)
-            }
-            Token::UnaryOp(op, inner) => write!(f, "UnaryOp({:?}, {:?})", op, inner),
-            Token::BinaryOp(op, first, second) => {
-                write!(f, "BinaryOp({:?}, {:?}, {:?})", op, first, second)
+        enum Item<'a> {
+            Token(&'a Token),
+            Text(&'static str),
+        }
+        let mut stack = vec![Item::Token(self)];
+        while let Some(item) = stack.pop() {
+            let token = match item {
+                Item::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Item::Token(token) => token,
+            };
+            match token {
+                Token::Bool(b) => write!(f, "Bool({})", b)?,
+                Token::Int(i) => write!(f, "Int({})", i)?,
+                Token::String(s) => {
+                    let s = String::from_utf8(s.clone()).unwrap();
+                    write!(f, "String({})", s)?
+                }
+                Token::UnaryOp(op, _) => write!(f, "UnaryOp({:?}, ", op)?,
+                Token::BinaryOp(op, _, _) => write!(f, "BinaryOp({:?}, ", op)?,
+                Token::If(..) => write!(f, "If(")?,
+                Token::Abstraction(i, _) => write!(f, "Abstraction({}, ", i)?,
+                Token::Application(..) => write!(f, "Application(")?,
+                Token::Id(i) => write!(f, "UseVar({})", i)?,
             }
-            Token::If(cond, first, second) => {
-                write!(f, "If({:?}, {:?}, {:?})", cond, first, second)
+            let children = children(token);
+            if !children.is_empty() {
+                stack.push(Item::Text(")"));
+                for (i, child) in children.into_iter().enumerate().rev() {
+                    stack.push(Item::Token(child));
+                    if i > 0 {
+                        stack.push(Item::Text(", "));
+                    }
+                }
             }
-            Token::Abstraction(i, inner) => write!(f, "Abstraction({}, {:?})", i, inner),
-            Token::Application(lhs, rhs) => write!(f, "Application({:?}, {:?})", lhs, rhs),
-            Token::Id(i) => write!(f, "UseVar({})", i),
         }
+        Ok(())
     }
 }
 
@@ -114,17 +214,87 @@ fn base() -> Int {
     Int::from(94)
 }
 const START: u8 = 33;
-const ALPH: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!\"#$%&'()*+,-./:;<=>?@[\\]^_`|~ \n";
+pub const ALPH: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!\"#$%&'()*+,-./:;<=>?@[\\]^_`|~ \n";
 
 pub fn encode_string(s: &str) -> String {
     let mut res = vec![b'S'];
-    for c in s.chars() {
-        let pos = ALPH.find(c).unwrap();
+    encode_bytes(s.as_bytes(), &mut res);
+    String::from_utf8(res).unwrap()
+}
+
+fn encode_bytes(s: &[u8], res: &mut Vec<u8>) {
+    for &c in s {
+        let pos = ALPH.find(c as char).unwrap();
         res.push(pos as u8 + START);
     }
+}
+
+pub(crate) fn encode_integer(n: &Int, res: &mut Vec<u8>) {
+    let (_, digits) = n.to_radix_be(94);
+    res.extend(digits.into_iter().map(|d| d + START));
+}
+
+/// Serializes `token` to ICFP text, the inverse of `parse_string`.
+/// Negative integers are written as `U-` applied to the absolute value.
+pub fn encode_token(token: &Token) -> String {
+    let mut res = vec![];
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        if !res.is_empty() {
+            res.push(b' ');
+        }
+        token.head().encode(&mut res);
+        stack.extend(children(token).into_iter().rev().map(|c| c.as_ref()));
+    }
     String::from_utf8(res).unwrap()
 }
 
+/// A node without its children, shared by the serializers of `Token` and
+/// `Term`.
+pub(crate) enum Head<'a> {
+    Bool(bool),
+    Int(&'a Int),
+    String(&'a [u8]),
+    UnaryOp(&'a UnaryOp),
+    BinaryOp(&'a BinaryOp),
+    If,
+    Application,
+    Abstraction(usize),
+    Id(usize),
+}
+
+impl Head<'_> {
+    pub(crate) fn encode(&self, res: &mut Vec<u8>) {
+        match *self {
+            Head::Bool(true) => res.push(b'T'),
+            Head::Bool(false) => res.push(b'F'),
+            Head::Int(i) => {
+                if i.sign() == Sign::Minus {
+                    res.extend_from_slice(b"U- ");
+                }
+                res.push(b'I');
+                encode_integer(i, res);
+            }
+            Head::String(s) => {
+                res.push(b'S');
+                encode_bytes(s, res);
+            }
+            Head::UnaryOp(op) => res.extend_from_slice(&[b'U', op.symbol()]),
+            Head::BinaryOp(op) => res.extend_from_slice(&[b'B', op.symbol()]),
+            Head::If => res.push(b'?'),
+            Head::Application => res.extend_from_slice(b"B$"),
+            Head::Abstraction(var) => {
+                res.push(b'L');
+                encode_integer(&Int::from(var), res);
+            }
+            Head::Id(var) => {
+                res.push(b'v');
+                encode_integer(&Int::from(var), res);
+            }
+        }
+    }
+}
+
 fn parse_integer(s: &[u8]) -> Int {
     let mut res = Int::ZERO;
     for &c in s {
@@ -133,208 +303,1487 @@ fn parse_integer(s: &[u8]) -> Int {
     res
 }
 
-pub fn parse(tokens: &mut VecDeque<Vec<u8>>) -> Token {
-    let s = tokens.pop_front().unwrap();
-    let indicator = s[0];
-    match indicator {
+/// Which operation failed, printed with its ICFP indicator.
+#[derive(Debug, Clone)]
+pub enum Operator {
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    If,
+    Application,
+}
+
+impl Operator {
+    fn of(token: &Token) -> Self {
+        match token {
+            Token::UnaryOp(op, _) => Operator::Unary(op.clone()),
+            Token::BinaryOp(op, _, _) => Operator::Binary(op.clone()),
+            Token::If(..) => Operator::If,
+            _ => Operator::Application,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operator::Unary(op) => write!(f, "U{}", op.symbol() as char),
+            Operator::Binary(op) => write!(f, "B{}", op.symbol() as char),
+            Operator::If => write!(f, "?"),
+            Operator::Application => write!(f, "B$"),
+        }
+    }
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> u8 {
+        match self {
+            UnaryOp::NegInteger => b'-',
+            UnaryOp::Not => b'!',
+            UnaryOp::StringToInt => b'#',
+            UnaryOp::IntToString => b'$',
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn symbol(&self) -> u8 {
+        match self {
+            BinaryOp::Add => b'+',
+            BinaryOp::Sub => b'-',
+            BinaryOp::Mul => b'*',
+            BinaryOp::Div => b'/',
+            BinaryOp::Mod => b'%',
+            BinaryOp::Less => b'<',
+            BinaryOp::More => b'>',
+            BinaryOp::Eq => b'=',
+            BinaryOp::Or => b'|',
+            BinaryOp::And => b'&',
+            BinaryOp::Concat => b'.',
+            BinaryOp::Prefix => b'T',
+            BinaryOp::Drop => b'D',
+        }
+    }
+}
+
+/// Offsets are token indices in the source (tokens are separated by
+/// whitespace), positions are byte offsets. Offsets are known for every parse
+/// error; for evaluation errors only when the failing sub-expression is still
+/// a node of the parsed program, which is not the case for terms created by
+/// call-by-name substitution.
+#[derive(Debug, Clone)]
+pub enum EvalError {
+    Parse {
+        offset: usize,
+        position: usize,
+        message: String,
+    },
+    TypeMismatch {
+        op: Operator,
+        expected: &'static str,
+        got: Token,
+        expr: Token,
+        offset: Option<usize>,
+    },
+    InvalidOperand {
+        op: Operator,
+        message: String,
+        expr: Token,
+        offset: Option<usize>,
+    },
+    /// Evaluation was stopped by `EvalContext::beta_limit` or `time_limit`.
+    LimitExceeded {
+        beta_reductions: usize,
+        elapsed: Duration,
+    },
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let fmt_offset = |offset: &Option<usize>| match offset {
+            Some(offset) => format!("token {}", offset),
+            None => "unknown token".to_string(),
+        };
+        match self {
+            EvalError::Parse {
+                offset,
+                position,
+                message,
+            } => write!(
+                f,
+                "Parse error at token {} (byte {}): {}",
+                offset, position, message
+            ),
+            EvalError::TypeMismatch {
+                op,
+                expected,
+                got,
+                expr,
+                offset,
+            } => write!(
+                f,
+                "{} at {}: expected {}, got {:?} in {}",
+                op,
+                fmt_offset(offset),
+                expected,
+                got,
+                decompile_truncated(expr, 60)
+            ),
+            EvalError::InvalidOperand {
+                op,
+                message,
+                expr,
+                offset,
+            } => write!(
+                f,
+                "{} at {}: {} in {}",
+                op,
+                fmt_offset(offset),
+                message,
+                decompile_truncated(expr, 60)
+            ),
+            EvalError::LimitExceeded {
+                beta_reductions,
+                elapsed,
+            } => write!(
+                f,
+                "Evaluation limit exceeded after {} beta reductions in {:?}",
+                beta_reductions, elapsed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// A token of the source text: the indicator byte and the rest of the token,
+/// borrowed from the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawToken<'a> {
+    pub indicator: u8,
+    pub body: &'a [u8],
+    /// Index of the token in the source.
+    pub offset: usize,
+    /// Byte offset of the token in the source.
+    pub position: usize,
+}
+
+/// Splits the source on any ASCII whitespace without copying it.
+pub struct Tokenizer<'a> {
+    input: &'a [u8],
+    pos: usize,
+    count: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            count: 0,
+        }
+    }
+
+    /// Index of the next token.
+    pub fn offset(&self) -> usize {
+        self.count
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = RawToken<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let input = self.input;
+        let start = self.pos
+            + input[self.pos..]
+                .iter()
+                .position(|c| !c.is_ascii_whitespace())?;
+        let end = input[start..]
+            .iter()
+            .position(|c| c.is_ascii_whitespace())
+            .map_or(input.len(), |len| start + len);
+        self.pos = end;
+        self.count += 1;
+        Some(RawToken {
+            indicator: input[start],
+            body: &input[start + 1..end],
+            offset: self.count - 1,
+            position: start,
+        })
+    }
+}
+
+#[cfg(test)]
+pub fn parse_string(input: &str) -> Token {
+    try_parse_string(input).unwrap_or_else(|err| panic!("{}", err))
+}
+
+pub fn try_parse_string(input: &str) -> Result<Token, EvalError> {
+    try_parse_bytes(input.as_bytes())
+}
+
+// A node whose operands are still being parsed.
+enum Pending {
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    Application,
+    If,
+    Abstraction(usize),
+}
+
+impl Pending {
+    fn arity(&self) -> usize {
+        match self {
+            Pending::Unary(_) | Pending::Abstraction(_) => 1,
+            Pending::Binary(_) | Pending::Application => 2,
+            Pending::If => 3,
+        }
+    }
+
+    fn build(self, operands: Vec<Rc<Token>>) -> Token {
+        let mut operands = operands.into_iter();
+        let mut operand = || operands.next().unwrap();
+        match self {
+            Pending::Unary(op) => Token::UnaryOp(op, operand()),
+            Pending::Binary(op) => Token::BinaryOp(op, operand(), operand()),
+            Pending::Application => Token::Application(operand(), operand()),
+            Pending::If => Token::If(operand(), operand(), operand()),
+            Pending::Abstraction(var) => Token::Abstraction(var, operand()),
+        }
+    }
+}
+
+enum Parsed {
+    Leaf(Token),
+    Node(Pending),
+}
+
+pub fn try_parse_bytes(input: &[u8]) -> Result<Token, EvalError> {
+    let mut tokens = Tokenizer::new(input);
+    // nodes are built bottom-up with an explicit stack, so deeply nested
+    // programs don't overflow the call stack
+    let mut stack: Vec<(Pending, Vec<Rc<Token>>)> = vec![];
+    let res = 'parse: loop {
+        let Some(token) = tokens.next() else {
+            return Err(EvalError::Parse {
+                offset: tokens.offset(),
+                position: input.len(),
+                message: "Unexpected end of input".to_string(),
+            });
+        };
+        let mut node = match parse_token(&token)? {
+            Parsed::Leaf(leaf) => leaf,
+            Parsed::Node(pending) => {
+                let arity = pending.arity();
+                stack.push((pending, Vec::with_capacity(arity)));
+                continue;
+            }
+        };
+        loop {
+            let Some((pending, operands)) = stack.last_mut() else {
+                break 'parse node;
+            };
+            operands.push(Rc::new(node));
+            if operands.len() < pending.arity() {
+                continue 'parse;
+            }
+            let (pending, operands) = stack.pop().unwrap();
+            node = pending.build(operands);
+        }
+    };
+    if let Some(token) = tokens.next() {
+        let left = 1 + tokens.count();
+        return Err(parse_err(
+            &token,
+            format!("{} tokens left after the expression", left),
+        ));
+    }
+    Ok(res)
+}
+
+fn parse_err(token: &RawToken, message: String) -> EvalError {
+    EvalError::Parse {
+        offset: token.offset,
+        position: token.position,
+        message,
+    }
+}
+
+fn check_chars(token: &RawToken) -> Result<(), EvalError> {
+    match token.body.iter().find(|c| !(START..START + 94).contains(c)) {
+        Some(c) => Err(parse_err(token, format!("Invalid char: {:#04x}", c))),
+        None => Ok(()),
+    }
+}
+
+fn parse_var(token: &RawToken) -> Result<usize, EvalError> {
+    if token.body.is_empty() {
+        return Err(parse_err(token, "Missing variable number".to_string()));
+    }
+    parse_integer(token.body)
+        .try_into()
+        .map_err(|_| parse_err(token, "Variable number is too large".to_string()))
+}
+
+fn parse_token(token: &RawToken) -> Result<Parsed, EvalError> {
+    check_chars(token)?;
+    let operator = || match token.body {
+        [c] => Ok(*c),
+        [] => Err(parse_err(
+            token,
+            format!("Missing operator in {}", token.indicator as char),
+        )),
+        _ => Err(parse_err(
+            token,
+            format!(
+                "Unexpected chars after operator in {}",
+                token.indicator as char
+            ),
+        )),
+    };
+    let leaf = match token.indicator {
+        b'T' | b'F' if !token.body.is_empty() => {
+            return Err(parse_err(
+                token,
+                "Unexpected chars after boolean".to_string(),
+            ))
+        }
         b'T' => Token::Bool(true),
         b'F' => Token::Bool(false),
-        b'I' => Token::Int(parse_integer(&s[1..])),
-        b'S' => {
-            let mut str = vec![];
-            for i in 1..s.len() {
-                let pos = s[i] - START;
-                str.push(ALPH.as_bytes()[pos as usize]);
-            }
-            Token::String(str)
+        b'I' if token.body.is_empty() => {
+            return Err(parse_err(token, "Missing integer digits".to_string()))
         }
+        b'I' => Token::Int(parse_integer(token.body)),
+        b'S' => Token::String(
+            token
+                .body
+                .iter()
+                .map(|&c| ALPH.as_bytes()[(c - START) as usize])
+                .collect(),
+        ),
         b'U' => {
-            let inner = Rc::new(parse(tokens));
-            match s[1] {
-                b'-' => Token::UnaryOp(UnaryOp::NegInteger, inner),
-                b'!' => Token::UnaryOp(UnaryOp::Not, inner),
-                b'#' => Token::UnaryOp(UnaryOp::StringToInt, inner),
-                b'$' => Token::UnaryOp(UnaryOp::IntToString, inner),
-                _ => panic!("Invalid unary operator: {}", s[1]),
-            }
+            let op = match operator()? {
+                b'-' => UnaryOp::NegInteger,
+                b'!' => UnaryOp::Not,
+                b'#' => UnaryOp::StringToInt,
+                b'$' => UnaryOp::IntToString,
+                c => return Err(parse_err(token, format!("Invalid unary operator: {}", c))),
+            };
+            return Ok(Parsed::Node(Pending::Unary(op)));
         }
         b'B' => {
-            let first = Rc::new(parse(tokens));
-            let second = Rc::new(parse(tokens));
-            match s[1] {
-                b'+' => Token::BinaryOp(BinaryOp::Add, first, second),
-                b'-' => Token::BinaryOp(BinaryOp::Sub, first, second),
-                b'*' => Token::BinaryOp(BinaryOp::Mul, first, second),
-                b'/' => Token::BinaryOp(BinaryOp::Div, first, second),
-                b'%' => Token::BinaryOp(BinaryOp::Mod, first, second),
-                b'<' => Token::BinaryOp(BinaryOp::Less, first, second),
-                b'>' => Token::BinaryOp(BinaryOp::More, first, second),
-                b'=' => Token::BinaryOp(BinaryOp::Eq, first, second),
-                b'|' => Token::BinaryOp(BinaryOp::Or, first, second),
-                b'&' => Token::BinaryOp(BinaryOp::And, first, second),
-                b'.' => Token::BinaryOp(BinaryOp::Concat, first, second),
-                b'T' => Token::BinaryOp(BinaryOp::Prefix, first, second),
-                b'D' => Token::BinaryOp(BinaryOp::Drop, first, second),
-                b'$' => Token::Application(first, second),
-                _ => panic!("Invalid binary operator: {}", s[1]),
-            }
-        }
-        b'?' => {
-            let cond = Rc::new(parse(tokens));
-            let first = Rc::new(parse(tokens));
-            let second = Rc::new(parse(tokens));
-            Token::If(cond, first, second)
+            let op = match operator()? {
+                b'+' => BinaryOp::Add,
+                b'-' => BinaryOp::Sub,
+                b'*' => BinaryOp::Mul,
+                b'/' => BinaryOp::Div,
+                b'%' => BinaryOp::Mod,
+                b'<' => BinaryOp::Less,
+                b'>' => BinaryOp::More,
+                b'=' => BinaryOp::Eq,
+                b'|' => BinaryOp::Or,
+                b'&' => BinaryOp::And,
+                b'.' => BinaryOp::Concat,
+                b'T' => BinaryOp::Prefix,
+                b'D' => BinaryOp::Drop,
+                b'$' => return Ok(Parsed::Node(Pending::Application)),
+                c => return Err(parse_err(token, format!("Invalid binary operator: {}", c))),
+            };
+            return Ok(Parsed::Node(Pending::Binary(op)));
         }
+        b'?' => return Ok(Parsed::Node(Pending::If)),
         b'L' => {
-            let i: usize = parse_integer(&s[1..]).try_into().unwrap();
-            let inner = Rc::new(parse(tokens));
-            Token::Abstraction(i as usize, inner)
+            return Ok(Parsed::Node(Pending::Abstraction(parse_var(token)?)));
         }
-        b'v' => {
-            let i: usize = parse_integer(&s[1..]).try_into().unwrap();
-            Token::Id(i as usize)
+        b'v' => Token::Id(parse_var(token)?),
+        c => return Err(parse_err(token, format!("Invalid token: {}", c))),
+    };
+    Ok(Parsed::Leaf(leaf))
+}
+
+#[cfg(test)]
+pub fn eval(token: &Token) -> Token {
+    eval_with(token, Strategy::Name)
+}
+
+pub fn try_eval(token: &Token) -> Result<Token, EvalError> {
+    try_eval_with(token, Strategy::Name)
+}
+
+/// How arguments of an application are passed to the function body. All of
+/// them run on an explicit stack, recursion depth is only bounded by memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Substitute the unevaluated argument (`eval`), every use re-evaluates it.
+    Name,
+    /// Bind the argument to a shared thunk, evaluated at most once.
+    Need,
+    /// `Need` with `B$ Y F` evaluated natively and the results of the fixpoint
+    /// functions cached by their arguments. Arguments are forced before the
+    /// call, and the cache is only used when all of them are literals.
+    /// Stricter than `Need`: a call fails when an argument fails or diverges,
+    /// even if the body doesn't use it.
+    Memo,
+}
+
+#[cfg(test)]
+pub fn eval_with(token: &Token, strategy: Strategy) -> Token {
+    try_eval_with(token, strategy).unwrap_or_else(|err| panic!("{}", err))
+}
+
+pub fn try_eval_with(token: &Token, strategy: Strategy) -> Result<Token, EvalError> {
+    EvalContext::new(strategy).eval(token)
+}
+
+/// Beta reductions allowed by the contest for a submitted program.
+pub const BETA_REDUCTIONS_LIMIT: usize = 10_000_000;
+
+/// Evaluation settings and the cost of the last `eval` call.
+#[derive(Debug, Clone)]
+pub struct EvalContext {
+    pub strategy: Strategy,
+    pub beta_limit: Option<usize>,
+    pub time_limit: Option<Duration>,
+    pub beta_reductions: usize,
+    pub elapsed: Duration,
+    /// Calls of fixpoint functions with literal arguments and how many of them
+    /// were answered from the cache, only counted with `Strategy::Memo`.
+    pub memo_calls: usize,
+    pub memo_hits: usize,
+    /// Logs the reduction steps, shared so that it can be read after `eval`.
+    pub tracer: Option<Rc<RefCell<Tracer>>>,
+}
+
+impl EvalContext {
+    pub fn new(strategy: Strategy) -> Self {
+        Self {
+            strategy,
+            beta_limit: None,
+            time_limit: None,
+            beta_reductions: 0,
+            elapsed: Duration::ZERO,
+            memo_calls: 0,
+            memo_hits: 0,
+            tracer: None,
         }
-        _ => panic!("Invalid token: {}", indicator),
     }
-}
 
-pub fn parse_string(input: &str) -> Token {
-    let mut words: VecDeque<Vec<u8>> = input
-        .split_whitespace()
-        .map(|s| s.as_bytes().to_vec())
-        .collect();
-    let res = parse(&mut words);
-    assert!(words.is_empty(), "left: {:?}", words);
-    res
+    pub fn with_beta_limit(mut self, limit: usize) -> Self {
+        self.beta_limit = Some(limit);
+        self
+    }
+
+    pub fn with_time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(Rc::new(RefCell::new(tracer)));
+        self
+    }
+
+    /// Evaluates `token`, `beta_reductions` and `elapsed` are updated even
+    /// when evaluation fails.
+    pub fn eval(&mut self, token: &Token) -> Result<Token, EvalError> {
+        let start = Instant::now();
+        let root = Rc::new(token.clone());
+        let evaluator = Evaluator {
+            root: &root,
+            beta_reductions: Cell::new(0),
+            beta_limit: self.beta_limit.unwrap_or(usize::MAX),
+            start,
+            time_limit: self.time_limit,
+            memo: (self.strategy == Strategy::Memo).then(RefCell::default),
+            tracer: self.tracer.as_deref(),
+        };
+        let res = match self.strategy {
+            Strategy::Name => evaluator.eval_name(&root),
+            Strategy::Need | Strategy::Memo => evaluator
+                .eval_need(&root, &None)
+                .map(|value| Readback::new(&root).value(&value)),
+        };
+        self.beta_reductions = evaluator.beta_reductions.get();
+        if let Some(memo) = &evaluator.memo {
+            let memo = memo.borrow();
+            self.memo_calls = memo.calls;
+            self.memo_hits = memo.hits;
+        }
+        self.elapsed = start.elapsed();
+        res
+    }
 }
 
-pub fn eval(token: &Token) -> Token {
+pub(crate) fn children(token: &Token) -> Vec<&Rc<Token>> {
     match token {
-        Token::Bool(_) | Token::Int(_) | Token::String(_) | Token::Id(_) => token.clone(),
-        Token::UnaryOp(op, inner) => {
-            let inner = eval(inner);
-            match op {
-                UnaryOp::NegInteger => Token::Int(-inner.int()),
-                UnaryOp::Not => Token::Bool(!inner.bool()),
-                UnaryOp::StringToInt => {
-                    let mut res = Int::ZERO;
-                    for c in inner.string() {
-                        let pos = ALPH.find(c as char).unwrap();
-                        res = res * base() + Int::from(pos);
+        Token::Bool(_) | Token::Int(_) | Token::String(_) | Token::Id(_) => vec![],
+        Token::UnaryOp(_, inner) | Token::Abstraction(_, inner) => vec![inner],
+        Token::BinaryOp(_, first, second) | Token::Application(first, second) => {
+            vec![first, second]
+        }
+        Token::If(cond, first, second) => vec![cond, first, second],
+    }
+}
+
+/// Drops `token` without recursing once per level as the derived drop does.
+pub fn drop_deep(token: Token) {
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        let children = match token {
+            Token::UnaryOp(_, inner) | Token::Abstraction(_, inner) => vec![inner],
+            Token::BinaryOp(_, first, second) | Token::Application(first, second) => {
+                vec![first, second]
+            }
+            Token::If(cond, first, second) => vec![cond, first, second],
+            Token::Bool(_) | Token::Int(_) | Token::String(_) | Token::Id(_) => vec![],
+        };
+        stack.extend(children.into_iter().filter_map(|c| Rc::try_unwrap(c).ok()));
+    }
+}
+
+// Prefix notation means the token offset of a node is its pre-order index.
+pub(crate) fn offset_of(root: &Token, expr: &Token) -> Option<usize> {
+    let mut stack = vec![root];
+    let mut offset = 0;
+    while let Some(token) = stack.pop() {
+        if std::ptr::eq(token, expr) {
+            return Some(offset);
+        }
+        offset += 1;
+        stack.extend(children(token).into_iter().rev().map(|c| c.as_ref()));
+    }
+    None
+}
+
+// Continuations of the call-by-name evaluator, see `Frame`.
+enum NameFrame {
+    Unary(Rc<Token>),
+    BinaryFirst(Rc<Token>),
+    BinarySecond(Rc<Token>, Token),
+    If(Rc<Token>),
+    // the function is being evaluated, then substitute the argument
+    Apply(Rc<Token>),
+}
+
+enum NameControl {
+    Eval(Rc<Token>),
+    Return(Token),
+}
+
+struct Evaluator<'a> {
+    root: &'a Token,
+    beta_reductions: Cell<usize>,
+    beta_limit: usize,
+    start: Instant,
+    time_limit: Option<Duration>,
+    memo: Option<RefCell<Memo>>,
+    tracer: Option<&'a RefCell<Tracer>>,
+}
+
+impl Evaluator<'_> {
+    fn trace(&self, kind: StepKind, depth: usize, redex: impl FnOnce() -> Token) {
+        if let Some(tracer) = self.tracer {
+            tracer.borrow_mut().record(kind, depth, redex);
+        }
+    }
+
+    fn beta_reduction(&self) -> Result<(), EvalError> {
+        let beta_reductions = self.beta_reductions.get() + 1;
+        self.beta_reductions.set(beta_reductions);
+        let out_of_time =
+            |limit| beta_reductions.is_multiple_of(1024) && self.start.elapsed() > limit;
+        if beta_reductions > self.beta_limit || self.time_limit.is_some_and(out_of_time) {
+            return Err(EvalError::LimitExceeded {
+                beta_reductions,
+                elapsed: self.start.elapsed(),
+            });
+        }
+        Ok(())
+    }
+
+    fn mismatch(&self, expr: &Token, expected: &'static str, got: Token) -> EvalError {
+        EvalError::TypeMismatch {
+            op: Operator::of(expr),
+            expected,
+            got,
+            expr: expr.clone(),
+            offset: offset_of(self.root, expr),
+        }
+    }
+
+    fn invalid(&self, expr: &Token, message: String) -> EvalError {
+        EvalError::InvalidOperand {
+            op: Operator::of(expr),
+            message,
+            expr: expr.clone(),
+            offset: offset_of(self.root, expr),
+        }
+    }
+
+    fn int(&self, expr: &Token, token: Token) -> Result<Int, EvalError> {
+        match token {
+            Token::Int(i) => Ok(i),
+            _ => Err(self.mismatch(expr, "Int", token)),
+        }
+    }
+
+    fn bool(&self, expr: &Token, token: Token) -> Result<bool, EvalError> {
+        match token {
+            Token::Bool(b) => Ok(b),
+            _ => Err(self.mismatch(expr, "Bool", token)),
+        }
+    }
+
+    fn string(&self, expr: &Token, token: Token) -> Result<Vec<u8>, EvalError> {
+        match token {
+            Token::String(s) => Ok(s),
+            _ => Err(self.mismatch(expr, "String", token)),
+        }
+    }
+
+    // `take` and `drop` clamp the index to the string.
+    fn index(&self, expr: &Token, token: Token, len: usize) -> Result<usize, EvalError> {
+        let i = self.int(expr, token)?;
+        Ok(if i.sign() == Sign::Minus {
+            0
+        } else {
+            i.try_into().map_or(len, |i: usize| i.min(len))
+        })
+    }
+
+    // Substitutes unevaluated arguments, on an explicit stack like
+    // `eval_need`.
+    fn eval_name(&self, token: &Rc<Token>) -> Result<Token, EvalError> {
+        let mut stack: Vec<NameFrame> = vec![];
+        let mut control = NameControl::Eval(token.clone());
+        loop {
+            control = match control {
+                NameControl::Eval(token) => match token.as_ref() {
+                    Token::Bool(_)
+                    | Token::Int(_)
+                    | Token::String(_)
+                    | Token::Id(_)
+                    | Token::Abstraction(..) => NameControl::Return(token.as_ref().clone()),
+                    Token::UnaryOp(_, inner) => {
+                        let inner = inner.clone();
+                        stack.push(NameFrame::Unary(token));
+                        NameControl::Eval(inner)
+                    }
+                    Token::BinaryOp(_, first, _) => {
+                        let first = first.clone();
+                        stack.push(NameFrame::BinaryFirst(token));
+                        NameControl::Eval(first)
+                    }
+                    Token::If(cond, _, _) => {
+                        let cond = cond.clone();
+                        stack.push(NameFrame::If(token));
+                        NameControl::Eval(cond)
+                    }
+                    Token::Application(e1, e2) => match e1.as_ref() {
+                        Token::Abstraction(i, inner) => {
+                            self.beta_reduction()?;
+                            self.trace(StepKind::Beta, stack.len(), || token.as_ref().clone());
+                            NameControl::Eval(substitute(inner, *i, e2.clone()))
+                        }
+                        _ => {
+                            let e1 = e1.clone();
+                            stack.push(NameFrame::Apply(token));
+                            NameControl::Eval(e1)
+                        }
+                    },
+                },
+                NameControl::Return(value) => {
+                    let Some(frame) = stack.pop() else {
+                        return Ok(value);
+                    };
+                    match frame {
+                        NameFrame::Unary(expr) => {
+                            let Token::UnaryOp(op, _) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            self.trace(StepKind::Unary, stack.len(), || {
+                                Token::UnaryOp(op.clone(), Rc::new(value.clone()))
+                            });
+                            NameControl::Return(self.eval_unary(&expr, op, value)?)
+                        }
+                        NameFrame::BinaryFirst(expr) => {
+                            let Token::BinaryOp(_, _, second) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let second = second.clone();
+                            stack.push(NameFrame::BinarySecond(expr, value));
+                            NameControl::Eval(second)
+                        }
+                        NameFrame::BinarySecond(expr, first) => {
+                            let Token::BinaryOp(op, _, _) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            self.trace(StepKind::Binary, stack.len(), || {
+                                Token::BinaryOp(
+                                    op.clone(),
+                                    Rc::new(first.clone()),
+                                    Rc::new(value.clone()),
+                                )
+                            });
+                            NameControl::Return(self.eval_binary(&expr, op, first, value)?)
+                        }
+                        NameFrame::If(expr) => {
+                            let Token::If(_, first, second) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let cond = self.bool(&expr, value)?;
+                            self.trace(StepKind::If(cond), stack.len(), || {
+                                Token::If(Rc::new(Token::Bool(cond)), first.clone(), second.clone())
+                            });
+                            NameControl::Eval(if cond { first.clone() } else { second.clone() })
+                        }
+                        NameFrame::Apply(expr) => {
+                            let Token::Application(_, e2) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let Token::Abstraction(i, inner) = &value else {
+                                return Err(self.mismatch(&expr, "Abstraction", value));
+                            };
+                            self.beta_reduction()?;
+                            self.trace(StepKind::Beta, stack.len(), || {
+                                Token::Application(Rc::new(value.clone()), e2.clone())
+                            });
+                            NameControl::Eval(substitute(inner, *i, e2.clone()))
+                        }
                     }
-                    Token::Int(res)
                 }
-                UnaryOp::IntToString => {
-                    let mut res = vec![];
-                    let mut n = inner.int();
-                    while n.sign() == Sign::Plus {
-                        let pos: usize = (n.clone() % base()).try_into().unwrap();
-                        res.push(ALPH.as_bytes()[pos]);
-                        n /= base();
+            };
+        }
+    }
+
+    fn eval_unary(&self, expr: &Token, op: &UnaryOp, inner: Token) -> Result<Token, EvalError> {
+        Ok(match op {
+            UnaryOp::NegInteger => Token::Int(-self.int(expr, inner)?),
+            UnaryOp::Not => Token::Bool(!self.bool(expr, inner)?),
+            UnaryOp::StringToInt => {
+                let mut res = Int::ZERO;
+                for c in self.string(expr, inner)? {
+                    let pos = ALPH.find(c as char).unwrap();
+                    res = res * base() + Int::from(pos);
+                }
+                Token::Int(res)
+            }
+            // the inverse of `StringToInt`, so 0 is "a" and not an empty string
+            UnaryOp::IntToString => {
+                let mut n = self.int(expr, inner)?;
+                if n.sign() == Sign::Minus {
+                    return Err(self.invalid(expr, format!("Negative number: {}", n)));
+                }
+                let mut res = vec![];
+                loop {
+                    let pos: usize = (n.clone() % base()).try_into().unwrap();
+                    res.push(ALPH.as_bytes()[pos]);
+                    n /= base();
+                    if n == Int::ZERO {
+                        break;
                     }
-                    res.reverse();
-                    Token::String(res)
                 }
+                res.reverse();
+                Token::String(res)
             }
-        }
-        Token::BinaryOp(op, first, second) => {
-            let first = eval(first);
-            let second = eval(second);
-            match op {
-                BinaryOp::Add => Token::Int(first.int() + second.int()),
-                BinaryOp::Sub => Token::Int(first.int() - second.int()),
-                BinaryOp::Mul => Token::Int(first.int() * second.int()),
-                BinaryOp::Div => Token::Int(first.int() / second.int()),
-                BinaryOp::Mod => Token::Int(first.int() % second.int()),
-                BinaryOp::Less => Token::Bool(first.int() < second.int()),
-                BinaryOp::More => Token::Bool(first.int() > second.int()),
-                BinaryOp::Eq => match (&first, &second) {
-                    (Token::Int(a), Token::Int(b)) => Token::Bool(a == b),
-                    (Token::Bool(a), Token::Bool(b)) => Token::Bool(a == b),
-                    (Token::String(a), Token::String(b)) => Token::Bool(a == b),
-                    _ => panic!("Invalid equality check: {:?} == {:?}", first, second),
-                },
-                BinaryOp::Or => Token::Bool(first.bool() || second.bool()),
-                BinaryOp::And => Token::Bool(first.bool() && second.bool()),
-                BinaryOp::Concat => {
-                    let mut res = first.string();
-                    res.extend(second.string());
-                    Token::String(res)
+        })
+    }
+
+    fn eval_binary(
+        &self,
+        expr: &Token,
+        op: &BinaryOp,
+        first: Token,
+        second: Token,
+    ) -> Result<Token, EvalError> {
+        let int = |token| self.int(expr, token);
+        let bool = |token| self.bool(expr, token);
+        Ok(match op {
+            BinaryOp::Add => Token::Int(int(first)? + int(second)?),
+            BinaryOp::Sub => Token::Int(int(first)? - int(second)?),
+            BinaryOp::Mul => Token::Int(int(first)? * int(second)?),
+            BinaryOp::Div | BinaryOp::Mod => {
+                let first = int(first)?;
+                let second = int(second)?;
+                if second == Int::ZERO {
+                    return Err(self.invalid(expr, "Division by zero".to_string()));
                 }
-                BinaryOp::Prefix => {
-                    let first: usize = first.int().try_into().unwrap();
-                    let res = second.string()[..first].to_vec();
-                    Token::String(res)
+                // BigInt division truncates toward zero and the remainder
+                // has the sign of the dividend, as in the spec
+                match op {
+                    BinaryOp::Div => Token::Int(first / second),
+                    _ => Token::Int(first % second),
+                }
+            }
+            BinaryOp::Less => Token::Bool(int(first)? < int(second)?),
+            BinaryOp::More => Token::Bool(int(first)? > int(second)?),
+            BinaryOp::Eq => match (&first, &second) {
+                (Token::Int(a), Token::Int(b)) => Token::Bool(a == b),
+                (Token::Bool(a), Token::Bool(b)) => Token::Bool(a == b),
+                (Token::String(a), Token::String(b)) => Token::Bool(a == b),
+                _ => {
+                    return Err(self.invalid(
+                        expr,
+                        format!("Invalid equality check: {:?} == {:?}", first, second),
+                    ))
                 }
-                BinaryOp::Drop => {
-                    let first: usize = first.int().try_into().unwrap();
-                    let res = second.string()[first..].to_vec();
-                    Token::String(res)
+            },
+            BinaryOp::Or => Token::Bool(bool(first)? || bool(second)?),
+            BinaryOp::And => Token::Bool(bool(first)? && bool(second)?),
+            BinaryOp::Concat => {
+                let mut res = self.string(expr, first)?;
+                res.extend(self.string(expr, second)?);
+                Token::String(res)
+            }
+            BinaryOp::Prefix | BinaryOp::Drop => {
+                let second = self.string(expr, second)?;
+                let first = self.index(expr, first, second.len())?;
+                match op {
+                    BinaryOp::Prefix => Token::String(second[..first].to_vec()),
+                    _ => Token::String(second[first..].to_vec()),
+                }
+            }
+        })
+    }
+}
+
+pub fn free_vars(token: &Token) -> HashSet<usize> {
+    let mut res = HashSet::new();
+    // the binders of the enclosing abstractions, popped after `None`
+    let mut bound = vec![];
+    let mut stack = vec![Some(token)];
+    while let Some(token) = stack.pop() {
+        match token {
+            None => {
+                bound.pop();
+            }
+            Some(Token::Id(i)) => {
+                if !bound.contains(i) {
+                    res.insert(*i);
                 }
             }
+            Some(Token::Abstraction(i, inner)) => {
+                bound.push(*i);
+                stack.push(None);
+                stack.push(Some(inner));
+            }
+            Some(token) => stack.extend(children(token).into_iter().map(|c| Some(c.as_ref()))),
+        }
+    }
+    res
+}
+
+// Largest variable number used in `token`, bound or free.
+fn max_var(token: &Token) -> usize {
+    let mut res = 0;
+    let mut stack = vec![token];
+    while let Some(token) = stack.pop() {
+        if let Token::Id(i) | Token::Abstraction(i, _) = token {
+            res = res.max(*i);
         }
-        Token::If(cond, first, second) => {
-            let cond = eval(cond);
-            if cond.bool() {
-                eval(first)
-            } else {
-                eval(second)
+        stack.extend(children(token).into_iter().map(|c| c.as_ref()));
+    }
+    res
+}
+
+struct Substitution {
+    var: usize,
+    with: Rc<Token>,
+    with_free: HashSet<usize>,
+}
+
+enum SubstTask {
+    Visit(Rc<Token>, Rc<Substitution>),
+    // apply the substitution to the last result
+    Then(Rc<Substitution>),
+    // rebuild the node from the results of its children, renaming the binder
+    // of an abstraction to the given variable
+    Build(Rc<Token>, Option<usize>),
+}
+
+// Capture-avoiding: a binder that would capture a free variable of `with` is
+// renamed to a fresh variable first. Returns `token` itself when `var` doesn't
+// occur in it, so untouched parts of the program keep their identity (and
+// their source offsets).
+pub(crate) fn substitute(token: &Rc<Token>, var: usize, with: Rc<Token>) -> Rc<Token> {
+    let with_free = free_vars(&with);
+    let subst = Rc::new(Substitution {
+        var,
+        with,
+        with_free,
+    });
+    let mut results: Vec<Rc<Token>> = vec![];
+    let mut tasks = vec![SubstTask::Visit(token.clone(), subst)];
+    while let Some(task) = tasks.pop() {
+        match task {
+            SubstTask::Visit(token, subst) => match token.as_ref() {
+                Token::Bool(_) | Token::Int(_) | Token::String(_) => results.push(token),
+                Token::Id(i) if *i == subst.var => results.push(subst.with.clone()),
+                Token::Id(_) => results.push(token),
+                // shadows, don't substitute
+                Token::Abstraction(i, _) if *i == subst.var => results.push(token),
+                Token::Abstraction(i, inner)
+                    if subst.with_free.contains(i) && free_vars(inner).contains(&subst.var) =>
+                {
+                    let fresh = max_var(inner).max(max_var(&subst.with)).max(subst.var) + 1;
+                    let rename = Rc::new(Substitution {
+                        var: *i,
+                        with: Rc::new(Token::Id(fresh)),
+                        with_free: HashSet::from([fresh]),
+                    });
+                    let inner = inner.clone();
+                    tasks.push(SubstTask::Build(token, Some(fresh)));
+                    tasks.push(SubstTask::Then(subst));
+                    tasks.push(SubstTask::Visit(inner, rename));
+                }
+                _ => {
+                    let children: Vec<Rc<Token>> =
+                        children(&token).into_iter().rev().cloned().collect();
+                    tasks.push(SubstTask::Build(token, None));
+                    for child in children {
+                        tasks.push(SubstTask::Visit(child, subst.clone()));
+                    }
+                }
+            },
+            SubstTask::Then(subst) => {
+                let token = results.pop().unwrap();
+                tasks.push(SubstTask::Visit(token, subst));
+            }
+            SubstTask::Build(token, binder) => {
+                let old = children(&token);
+                let new = results.split_off(results.len() - old.len());
+                if binder.is_none() && old.iter().zip(&new).all(|(o, n)| Rc::ptr_eq(o, n)) {
+                    results.push(token);
+                    continue;
+                }
+                let mut new = new.into_iter();
+                let mut next = || new.next().unwrap();
+                results.push(Rc::new(match token.as_ref() {
+                    Token::UnaryOp(op, _) => Token::UnaryOp(op.clone(), next()),
+                    Token::BinaryOp(op, _, _) => Token::BinaryOp(op.clone(), next(), next()),
+                    Token::If(..) => Token::If(next(), next(), next()),
+                    Token::Application(..) => Token::Application(next(), next()),
+                    Token::Abstraction(i, _) => Token::Abstraction(binder.unwrap_or(*i), next()),
+                    _ => unreachable!(),
+                }));
             }
         }
-        Token::Abstraction(i, inner) => {
-            // let inner = eval(inner);
-            Token::Abstraction(*i, inner.clone())
-            // Token::Abstraction(*i, Rc::new(inner))
+    }
+    results.pop().unwrap()
+}
+
+// Call-by-need evaluation: instead of substituting arguments into the body we
+// keep an environment of thunks, so the Y-combinator programs only evaluate
+// every argument once.
+
+type Env = Option<Rc<EnvNode>>;
+
+struct EnvNode {
+    var: usize,
+    thunk: Rc<RefCell<Thunk>>,
+    next: Env,
+}
+
+enum Thunk {
+    Delayed(Rc<Token>, Env),
+    InProgress,
+    Forced(Value),
+    // `F` of a natively evaluated `B$ Y F`, bound in the node itself. The
+    // reference is weak as the node would otherwise own itself.
+    Recursive(Rc<Token>, Weak<EnvNode>),
+}
+
+#[derive(Clone)]
+enum Value {
+    // Bool, Int, String or a free variable
+    Lit(Rc<Token>),
+    Closure(usize, Rc<Token>, Env),
+}
+
+fn lookup(env: &Env, var: usize) -> Option<Rc<RefCell<Thunk>>> {
+    let mut cur = env;
+    while let Some(node) = cur {
+        if node.var == var {
+            return Some(node.thunk.clone());
         }
-        Token::Application(e1, e2) => match e1.as_ref() {
-            Token::Abstraction(i, inner) => {
-                let inner = substitute(inner, *i, e2.clone());
-                eval(&inner)
+        cur = &node.next;
+    }
+    None
+}
+
+fn bind(env: &Env, var: usize, thunk: Thunk) -> Env {
+    Some(Rc::new(EnvNode {
+        var,
+        thunk: Rc::new(RefCell::new(thunk)),
+        next: env.clone(),
+    }))
+}
+
+// The innermost bodies of the fixpoint functions seen so far with their
+// parameters, and their results by the node binding the function and the
+// values of the parameters. Results keep the node alive, so its address is not
+// reused while it is a part of some key. The values are interned, so keys are
+// compared and hashed by `TermId`.
+#[derive(Default)]
+struct Memo {
+    bodies: HashMap<*const Token, Vec<usize>>,
+    args: Arena,
+    results: HashMap<MemoKey, (Rc<EnvNode>, Value)>,
+    calls: usize,
+    hits: usize,
+}
+
+type MemoKey = (*const EnvNode, Vec<TermId>);
+
+// The call-by-need evaluator is a machine with an explicit continuation
+// stack, so deep recursion in the program only grows `stack` on the heap.
+// Every frame keeps the node it was created for, both to find the next
+// sub-expression and to report errors.
+enum Frame {
+    // store the returned value into the thunk
+    Update(Rc<RefCell<Thunk>>),
+    Unary(Rc<Token>),
+    BinaryFirst(Rc<Token>, Env),
+    BinarySecond(Rc<Token>, Token),
+    If(Rc<Token>, Env),
+    // the function is being evaluated, then apply it to the argument
+    Apply(Rc<Token>, Env),
+    // the parameters of a memoized function body are being forced, the ones
+    // left are on top of the environment
+    MemoArgs {
+        body: Rc<Token>,
+        env: Env,
+        node: Rc<EnvNode>,
+        params: Vec<usize>,
+        args: Vec<TermId>,
+    },
+    // store the result of the memoized call
+    MemoStore(MemoKey, Rc<EnvNode>),
+}
+
+enum Control {
+    Eval(Rc<Token>, Env),
+    Return(Value),
+}
+
+impl Evaluator<'_> {
+    // For `B$ Y (L f. inner)` returns `f` and `inner`, registering the body of
+    // `inner` for memoization.
+    fn fix(&self, token: &Token) -> Option<(usize, Rc<Token>)> {
+        let Token::Abstraction(var, inner) = token.fix_function()?.as_ref() else {
+            return None;
+        };
+        let mut params = vec![];
+        let mut body = inner;
+        while let Token::Abstraction(param, inner) = body.as_ref() {
+            params.push(*param);
+            body = inner;
+        }
+        if !params.is_empty() {
+            let mut memo = self.memo.as_ref()?.borrow_mut();
+            memo.bodies.entry(Rc::as_ptr(body)).or_insert(params);
+        }
+        Some((*var, inner.clone()))
+    }
+
+    // When `body` is entered as the body of a memoized function, its
+    // parameters and the node binding the function. The environment is then
+    // the parameters, the last one on top, followed by that node. Equal bodies
+    // elsewhere in the program, shared by an `Arena`, don't match.
+    fn memo_call(&self, body: &Rc<Token>, env: &Env) -> Option<(Vec<usize>, Rc<EnvNode>)> {
+        let params = self
+            .memo
+            .as_ref()?
+            .borrow()
+            .bodies
+            .get(&Rc::as_ptr(body))?
+            .clone();
+        let mut node = env.as_ref()?;
+        for param in params.iter().rev() {
+            if node.var != *param {
+                return None;
             }
-            _ => {
-                let e1 = eval(e1);
-                let e2 = eval(e2);
-                eval(&Token::Application(Rc::new(e1), Rc::new(e2)))
+            node = node.next.as_ref()?;
+        }
+        let Thunk::Recursive(f, _) = &*node.thunk.borrow() else {
+            return None;
+        };
+        let mut inner = f;
+        while let Token::Abstraction(_, next) = inner.as_ref() {
+            inner = next;
+        }
+        Rc::ptr_eq(inner, body).then(|| (params, node.clone()))
+    }
+
+    fn lit(&self, expr: &Token, value: Value) -> Result<Token, EvalError> {
+        match value {
+            Value::Lit(token) => Ok(Rc::unwrap_or_clone(token)),
+            Value::Closure(i, inner, _) => {
+                Err(self.mismatch(expr, "value", Token::Abstraction(i, inner)))
             }
-        },
+        }
+    }
+
+    fn eval_need(&self, token: &Rc<Token>, env: &Env) -> Result<Value, EvalError> {
+        let mut stack: Vec<Frame> = vec![];
+        let mut control = Control::Eval(token.clone(), env.clone());
+        // set when a memoized body is entered after a cache miss
+        let mut entered = false;
+        loop {
+            control = match control {
+                Control::Eval(token, env)
+                    if !std::mem::take(&mut entered) && self.memo_call(&token, &env).is_some() =>
+                {
+                    let (mut params, node) = self.memo_call(&token, &env).unwrap();
+                    let param = params.pop().unwrap();
+                    stack.push(Frame::MemoArgs {
+                        body: token,
+                        env: env.clone(),
+                        node,
+                        params,
+                        args: vec![],
+                    });
+                    Control::Eval(Rc::new(Token::Id(param)), env)
+                }
+                Control::Eval(token, env) => match token.as_ref() {
+                    Token::Bool(_) | Token::Int(_) | Token::String(_) => {
+                        Control::Return(Value::Lit(token))
+                    }
+                    Token::Id(i) => match lookup(&env, *i) {
+                        Some(thunk) => {
+                            let state =
+                                std::mem::replace(&mut *thunk.borrow_mut(), Thunk::InProgress);
+                            match state {
+                                Thunk::Forced(value) => {
+                                    *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                                    Control::Return(value)
+                                }
+                                Thunk::Delayed(expr, env) => {
+                                    stack.push(Frame::Update(thunk));
+                                    Control::Eval(expr, env)
+                                }
+                                Thunk::Recursive(expr, node) => {
+                                    *thunk.borrow_mut() =
+                                        Thunk::Recursive(expr.clone(), node.clone());
+                                    Control::Eval(expr, node.upgrade())
+                                }
+                                Thunk::InProgress => unreachable!("Thunk depends on itself"),
+                            }
+                        }
+                        None => Control::Return(Value::Lit(token)),
+                    },
+                    Token::UnaryOp(_, inner) => {
+                        let inner = inner.clone();
+                        stack.push(Frame::Unary(token));
+                        Control::Eval(inner, env)
+                    }
+                    Token::BinaryOp(_, first, _) => {
+                        let first = first.clone();
+                        stack.push(Frame::BinaryFirst(token, env.clone()));
+                        Control::Eval(first, env)
+                    }
+                    Token::If(cond, _, _) => {
+                        let cond = cond.clone();
+                        stack.push(Frame::If(token, env.clone()));
+                        Control::Eval(cond, env)
+                    }
+                    Token::Abstraction(i, inner) => {
+                        Control::Return(Value::Closure(*i, inner.clone(), env))
+                    }
+                    Token::Application(..) if self.memo.is_some() && self.fix(&token).is_some() => {
+                        let (var, inner) = self.fix(&token).unwrap();
+                        self.beta_reduction()?;
+                        self.trace(StepKind::Beta, stack.len(), || token.as_ref().clone());
+                        let node = Rc::new_cyclic(|node| EnvNode {
+                            var,
+                            thunk: Rc::new(RefCell::new(Thunk::Recursive(
+                                inner.clone(),
+                                node.clone(),
+                            ))),
+                            next: env,
+                        });
+                        Control::Eval(inner, Some(node))
+                    }
+                    Token::Application(e1, _) => {
+                        let e1 = e1.clone();
+                        stack.push(Frame::Apply(token, env.clone()));
+                        Control::Eval(e1, env)
+                    }
+                },
+                Control::Return(value) => {
+                    let Some(frame) = stack.pop() else {
+                        return Ok(value);
+                    };
+                    match frame {
+                        Frame::Update(thunk) => {
+                            *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                            Control::Return(value)
+                        }
+                        Frame::Unary(expr) => {
+                            let Token::UnaryOp(op, _) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let inner = self.lit(&expr, value)?;
+                            self.trace(StepKind::Unary, stack.len(), || {
+                                Token::UnaryOp(op.clone(), Rc::new(inner.clone()))
+                            });
+                            let res = self.eval_unary(&expr, op, inner)?;
+                            Control::Return(Value::Lit(Rc::new(res)))
+                        }
+                        Frame::BinaryFirst(expr, env) => {
+                            let Token::BinaryOp(_, _, second) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let second = second.clone();
+                            let first = self.lit(&expr, value)?;
+                            stack.push(Frame::BinarySecond(expr, first));
+                            Control::Eval(second, env)
+                        }
+                        Frame::BinarySecond(expr, first) => {
+                            let Token::BinaryOp(op, _, _) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let second = self.lit(&expr, value)?;
+                            self.trace(StepKind::Binary, stack.len(), || {
+                                Token::BinaryOp(
+                                    op.clone(),
+                                    Rc::new(first.clone()),
+                                    Rc::new(second.clone()),
+                                )
+                            });
+                            let res = self.eval_binary(&expr, op, first, second)?;
+                            Control::Return(Value::Lit(Rc::new(res)))
+                        }
+                        Frame::If(expr, env) => {
+                            let Token::If(_, first, second) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            let cond = self.lit(&expr, value)?;
+                            let cond = self.bool(&expr, cond)?;
+                            self.trace(StepKind::If(cond), stack.len(), || {
+                                Token::If(Rc::new(Token::Bool(cond)), first.clone(), second.clone())
+                            });
+                            if cond {
+                                Control::Eval(first.clone(), env)
+                            } else {
+                                Control::Eval(second.clone(), env)
+                            }
+                        }
+                        Frame::Apply(expr, env) => {
+                            let Token::Application(_, e2) = expr.as_ref() else {
+                                unreachable!()
+                            };
+                            match value {
+                                Value::Closure(i, inner, closure_env) => {
+                                    self.beta_reduction()?;
+                                    self.trace(StepKind::Beta, stack.len(), || {
+                                        let f = Token::Abstraction(i, inner.clone());
+                                        Token::Application(Rc::new(f), e2.clone())
+                                    });
+                                    let closure_env =
+                                        bind(&closure_env, i, Thunk::Delayed(e2.clone(), env));
+                                    Control::Eval(inner, closure_env)
+                                }
+                                Value::Lit(lit) => {
+                                    return Err(self.mismatch(
+                                        &expr,
+                                        "Abstraction",
+                                        lit.as_ref().clone(),
+                                    ))
+                                }
+                            }
+                        }
+                        Frame::MemoArgs {
+                            body,
+                            env,
+                            node,
+                            mut params,
+                            mut args,
+                        } => {
+                            let Value::Lit(arg) = value else {
+                                // closures can't be compared, call without the cache
+                                entered = true;
+                                control = Control::Eval(body, env);
+                                continue;
+                            };
+                            let memo = self.memo.as_ref().unwrap();
+                            args.push(memo.borrow_mut().args.intern_token(&arg));
+                            if let Some(param) = params.pop() {
+                                stack.push(Frame::MemoArgs {
+                                    body,
+                                    env: env.clone(),
+                                    node,
+                                    params,
+                                    args,
+                                });
+                                control = Control::Eval(Rc::new(Token::Id(param)), env);
+                                continue;
+                            }
+                            let key = (Rc::as_ptr(&node), args);
+                            let mut memo = self.memo.as_ref().unwrap().borrow_mut();
+                            memo.calls += 1;
+                            if let Some(value) = memo.results.get(&key).map(|(_, v)| v.clone()) {
+                                memo.hits += 1;
+                                Control::Return(value)
+                            } else {
+                                stack.push(Frame::MemoStore(key, node));
+                                entered = true;
+                                Control::Eval(body, env)
+                            }
+                        }
+                        Frame::MemoStore(key, node) => {
+                            let mut memo = self.memo.as_ref().unwrap().borrow_mut();
+                            memo.results.insert(key, (node, value.clone()));
+                            Control::Return(value)
+                        }
+                    }
+                }
+            };
+        }
     }
 }
 
-fn substitute(token: &Token, var: usize, with: Rc<Token>) -> Rc<Token> {
-    match token {
-        Token::Bool(_) | Token::Int(_) | Token::String(_) => Rc::new(token.clone()),
-        Token::UnaryOp(op, inner) => {
-            Rc::new(Token::UnaryOp(op.clone(), substitute(inner, var, with)))
-        }
-        Token::BinaryOp(op, first, second) => Rc::new(Token::BinaryOp(
-            op.clone(),
-            substitute(first, var, with.clone()),
-            substitute(second, var, with),
-        )),
-        Token::If(cond, first, second) => Rc::new(Token::If(
-            substitute(cond, var, with.clone()),
-            substitute(first, var, with.clone()),
-            substitute(second, var, with),
-        )),
-        Token::Abstraction(i, inner) => {
-            if *i == var {
-                // shadows, don't substitute
-                Rc::new(Token::Abstraction(*i, inner.clone()))
-            } else {
-                Rc::new(Token::Abstraction(*i, substitute(inner, var, with)))
+// Converts values back to `Token`s, substituting closure environments into
+// lambda bodies. Substituted terms can only have variables free in the whole
+// program as free variables, so binders using one of those are renamed.
+struct Readback {
+    program_free: HashSet<usize>,
+    next_fresh: usize,
+}
+
+// Binders of the original term mapped to the ones in the result, innermost
+// first.
+type Bound = Option<Rc<BoundNode>>;
+
+struct BoundNode {
+    old: usize,
+    new: usize,
+    next: Bound,
+}
+
+enum ReadbackTask {
+    Visit(Rc<Token>, Env, Bound),
+    // rebuild the node from the results of its children, with the given
+    // binder for an abstraction
+    Build(Rc<Token>, usize),
+    // apply the fixpoint combinator to the last result
+    Fix(Token),
+}
+
+impl Readback {
+    fn new(root: &Token) -> Self {
+        Self {
+            program_free: free_vars(root),
+            next_fresh: max_var(root) + 1,
+        }
+    }
+
+    fn value(&mut self, value: &Value) -> Token {
+        let mut results: Vec<Rc<Token>> = vec![];
+        let mut tasks = vec![];
+        Self::push_value(value, &mut tasks, &mut results);
+        while let Some(task) = tasks.pop() {
+            match task {
+                ReadbackTask::Visit(token, env, bound) => {
+                    self.visit(token, env, bound, &mut tasks, &mut results)
+                }
+                ReadbackTask::Build(token, binder) => {
+                    let n = children(&token).len();
+                    let mut new = results.split_off(results.len() - n).into_iter();
+                    let mut next = || new.next().unwrap();
+                    results.push(Rc::new(match token.as_ref() {
+                        Token::UnaryOp(op, _) => Token::UnaryOp(op.clone(), next()),
+                        Token::BinaryOp(op, _, _) => Token::BinaryOp(op.clone(), next(), next()),
+                        Token::If(..) => Token::If(next(), next(), next()),
+                        Token::Application(..) => Token::Application(next(), next()),
+                        Token::Abstraction(..) => Token::Abstraction(binder, next()),
+                        _ => unreachable!(),
+                    }));
+                }
+                ReadbackTask::Fix(y) => {
+                    let f = results.pop().unwrap();
+                    results.push(Rc::new(Token::Application(Rc::new(y), f)));
+                }
             }
         }
-        Token::Id(i) => {
-            if *i == var {
-                with
-            } else {
-                Rc::new(Token::Id(*i))
+        Rc::unwrap_or_clone(results.pop().unwrap())
+    }
+
+    fn push_value(value: &Value, tasks: &mut Vec<ReadbackTask>, results: &mut Vec<Rc<Token>>) {
+        match value {
+            Value::Lit(token) => results.push(token.clone()),
+            Value::Closure(i, inner, env) => tasks.push(ReadbackTask::Visit(
+                Rc::new(Token::Abstraction(*i, inner.clone())),
+                env.clone(),
+                None,
+            )),
+        }
+    }
+
+    fn visit(
+        &mut self,
+        token: Rc<Token>,
+        env: Env,
+        bound: Bound,
+        tasks: &mut Vec<ReadbackTask>,
+        results: &mut Vec<Rc<Token>>,
+    ) {
+        match token.as_ref() {
+            Token::Bool(_) | Token::Int(_) | Token::String(_) => results.push(token),
+            Token::Id(i) => {
+                let mut cur = &bound;
+                while let Some(node) = cur {
+                    if node.old == *i {
+                        results.push(Rc::new(Token::Id(node.new)));
+                        return;
+                    }
+                    cur = &node.next;
+                }
+                let Some(thunk) = lookup(&env, *i) else {
+                    results.push(token);
+                    return;
+                };
+                match &*thunk.borrow() {
+                    Thunk::Forced(value) => Self::push_value(value, tasks, results),
+                    Thunk::Delayed(expr, env) => {
+                        tasks.push(ReadbackTask::Visit(expr.clone(), env.clone(), None))
+                    }
+                    Thunk::Recursive(expr, node) => {
+                        let node = node.upgrade().unwrap();
+                        let f = Rc::new(Token::Abstraction(node.var, expr.clone()));
+                        let y = Token::y_combinator(self.next_fresh, self.next_fresh + 1);
+                        self.next_fresh += 2;
+                        tasks.push(ReadbackTask::Fix(y));
+                        tasks.push(ReadbackTask::Visit(f, node.next.clone(), None));
+                    }
+                    Thunk::InProgress => unreachable!(),
+                };
+            }
+            Token::Abstraction(i, inner) => {
+                let new = if self.program_free.contains(i) {
+                    self.next_fresh += 1;
+                    self.next_fresh - 1
+                } else {
+                    *i
+                };
+                let bound = Some(Rc::new(BoundNode {
+                    old: *i,
+                    new,
+                    next: bound,
+                }));
+                let inner = inner.clone();
+                tasks.push(ReadbackTask::Build(token, new));
+                tasks.push(ReadbackTask::Visit(inner, env, bound));
+            }
+            _ => {
+                let children: Vec<Rc<Token>> =
+                    children(&token).into_iter().rev().cloned().collect();
+                tasks.push(ReadbackTask::Build(token, 0));
+                for child in children {
+                    tasks.push(ReadbackTask::Visit(child, env.clone(), bound.clone()));
+                }
             }
         }
-        Token::Application(lhs, rhs) => Rc::new(Token::Application(
-            substitute(lhs, var, with.clone()),
-            substitute(rhs, var, with),
-        )),
     }
 }
 
@@ -439,6 +1888,403 @@ fn lambdaman() {
     "#;
     let res = parse_string(input);
     eprintln!("Res: {:?}", res);
-    // let eval_res = eval(&res);
-    // eprintln!("Eval res: {:?}", eval_res);
+    let eval_res = eval_with(&res, Strategy::Need);
+    eprintln!("Eval res: {:?}", eval_res);
+    assert!(eval_res.string().starts_with(b"solve lambdaman11 "));
+}
+
+#[test]
+fn need_matches_name() {
+    let inputs = [
+        "B$ B$ L# L$ v# B. SB%,,/ S}Q/2,$_ IK",
+        "B$ B$ L# L! v# I& I$",
+        "B$ B$ L# L! v! I& I$",
+        "U$ I4%34",
+        "U# S4%34",
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+        r#"B$ B$ Lu B$ Lx B$ vu B$ vx vx Ly B$ vu B$ vy vy Lf Ln ? B= vn I" I" B* vn B$ vf B- vn I" I&"#,
+    ];
+    for input in inputs {
+        let res = parse_string(input);
+        let by_name = format!("{:?}", eval_with(&res, Strategy::Name));
+        let by_need = format!("{:?}", eval_with(&res, Strategy::Need));
+        assert_eq!(by_name, by_need, "input: {}", input);
+        let memo = format!("{:?}", eval_with(&res, Strategy::Memo));
+        assert_eq!(by_name, memo, "input: {}", input);
+    }
+}
+
+#[test]
+fn need_closure_readback() {
+    let res = parse_string("B$ L# L$ B+ v# v$ I#");
+    let eval_res = eval_with(&res, Strategy::Need);
+    eprintln!("Eval res: {:?}", eval_res);
+    assert_eq!(format!("{:?}", eval_res), format!("{:?}", eval(&res)));
+}
+
+#[test]
+fn need_efficiency01() {
+    // By name every `v!` is evaluated 4 times per level, 4^22 in total.
+    let input = fs::read_to_string("../efficiency/efficiency01.in").unwrap();
+    let res = parse_string(&input);
+    let eval_res = eval_with(&res, Strategy::Need);
+    eprintln!("Eval res: {:?}", eval_res);
+    assert_eq!(eval_res.int(), Int::from(4).pow(22));
+}
+
+#[test]
+fn parse_errors() {
+    match try_parse_string("B+ I\" U") {
+        Err(EvalError::Parse { offset, .. }) => assert_eq!(offset, 2),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match try_parse_string("B+ I\"") {
+        Err(EvalError::Parse { offset, .. }) => assert_eq!(offset, 2),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match try_parse_string("I\" I#") {
+        Err(EvalError::Parse { offset, .. }) => assert_eq!(offset, 1),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    let position = |input: &str| match try_parse_string(input) {
+        Err(EvalError::Parse {
+            offset, position, ..
+        }) => (offset, position),
+        res => panic!("Unexpected result: {:?}", res),
+    };
+    assert_eq!(position("B+  I\"\n\tU"), (2, 8));
+    assert_eq!(position("B+ I\" "), (2, 6));
+    assert_eq!(position("U- I\" I#  I$"), (2, 6));
+    assert_eq!(position("B$ L I\""), (1, 3));
+    assert_eq!(position("B+x I\" I\""), (0, 0));
+    assert_eq!(position("B$ I\" I"), (2, 6));
+    assert_eq!(position("U- I\u{e9}"), (1, 3));
+    assert_eq!(position(""), (0, 0));
+}
+
+#[test]
+fn parse_whitespace_and_deep_nesting() {
+    assert_eq!(
+        parse_string("\r\nB+\tI\"\x0c I#\n"),
+        parse_string("B+ I\" I#")
+    );
+
+    let depth = 10_000;
+    let program = "U- ".repeat(depth) + "I\"";
+    let mut token = try_parse_bytes(program.as_bytes()).unwrap();
+    let mut negations = 0;
+    while let Token::UnaryOp(UnaryOp::NegInteger, inner) = token {
+        token = Rc::try_unwrap(inner).unwrap();
+        negations += 1;
+    }
+    assert_eq!((negations, token), (depth, Token::Int(Int::from(1))));
+}
+
+#[test]
+fn eval_errors() {
+    for strategy in [Strategy::Name, Strategy::Need] {
+        let res = parse_string("B$ L# B+ I\" B* v# I# S4%34");
+        match try_eval_with(&res, strategy) {
+            Err(EvalError::TypeMismatch {
+                op: Operator::Binary(BinaryOp::Mul),
+                expected: "Int",
+                got: Token::String(s),
+                offset,
+                ..
+            }) => {
+                assert_eq!(s, b"test");
+                if strategy == Strategy::Need {
+                    assert_eq!(offset, Some(4));
+                }
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let res = parse_string("? T B/ I# I! I#");
+        match try_eval_with(&res, strategy) {
+            Err(err @ EvalError::InvalidOperand { offset, .. }) => {
+                assert_eq!(offset, Some(2));
+                assert_eq!(err.to_string(), "B/ at token 2: Division by zero in 2 / 0");
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
+
+#[test]
+fn encode_token_simple() {
+    for input in [
+        "B$ B$ L# L$ v# B. SB%,,/ S}Q/2,$_ IK",
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+        "? B< U- I$ U# S4%34 BT I# BD I! S4%34 U$ I!",
+    ] {
+        assert_eq!(encode_token(&parse_string(input)), input);
+    }
+    assert_eq!(encode_token(&Token::Int(Int::from(1337))), "I/6");
+    assert_eq!(encode_token(&Token::Int(Int::from(-1))), "U- I\"");
+}
+
+#[cfg(test)]
+fn random_token<R: rand::Rng>(rng: &mut R, depth: usize) -> Token {
+    let max_kind = if depth == 0 { 4 } else { 9 };
+    let sub = |rng: &mut R| Rc::new(random_token(rng, depth - 1));
+    match rng.gen_range(0..max_kind) {
+        0 => Token::Bool(rng.gen()),
+        1 => Token::Int(Int::from(rng.gen::<u64>() >> rng.gen_range(0..64))),
+        2 => {
+            let len = rng.gen_range(0..10);
+            let s = (0..len)
+                .map(|_| ALPH.as_bytes()[rng.gen_range(0..ALPH.len())])
+                .collect();
+            Token::String(s)
+        }
+        3 => Token::Id(rng.gen_range(0..200)),
+        4 => {
+            let op = [
+                UnaryOp::NegInteger,
+                UnaryOp::Not,
+                UnaryOp::StringToInt,
+                UnaryOp::IntToString,
+            ][rng.gen_range(0..4)]
+            .clone();
+            Token::UnaryOp(op, sub(rng))
+        }
+        5 => {
+            let op = [
+                BinaryOp::Add,
+                BinaryOp::Sub,
+                BinaryOp::Mul,
+                BinaryOp::Div,
+                BinaryOp::Mod,
+                BinaryOp::Less,
+                BinaryOp::More,
+                BinaryOp::Eq,
+                BinaryOp::Or,
+                BinaryOp::And,
+                BinaryOp::Concat,
+                BinaryOp::Prefix,
+                BinaryOp::Drop,
+            ][rng.gen_range(0..13)]
+            .clone();
+            let first = sub(rng);
+            Token::BinaryOp(op, first, sub(rng))
+        }
+        6 => {
+            let cond = sub(rng);
+            let first = sub(rng);
+            Token::If(cond, first, sub(rng))
+        }
+        7 => {
+            let first = sub(rng);
+            Token::Application(first, sub(rng))
+        }
+        _ => {
+            let var = rng.gen_range(0..200);
+            Token::Abstraction(var, sub(rng))
+        }
+    }
+}
+
+#[test]
+fn encode_token_roundtrip() {
+    use rand::SeedableRng;
+
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(787788);
+    for _ in 0..1000 {
+        let token = random_token(&mut rng, 6);
+        let encoded = encode_token(&token);
+        let parsed = parse_string(&encoded);
+        assert_eq!(parsed, token, "encoded: {}", encoded);
+        assert_eq!(encode_token(&parsed), encoded);
+    }
+}
+
+#[test]
+fn beta_reductions() {
+    let res = parse_string("B$ B$ L# L! v! I& I$");
+    for strategy in [Strategy::Name, Strategy::Need] {
+        let mut ctx = EvalContext::new(strategy);
+        assert_eq!(ctx.eval(&res).unwrap().int_usize(), 3);
+        assert_eq!(ctx.beta_reductions, 2);
+    }
+
+    let input = fs::read_to_string("../efficiency/efficiency01.in").unwrap();
+    let res = parse_string(&input);
+    let mut ctx = EvalContext::new(Strategy::Name).with_beta_limit(1000);
+    match ctx.eval(&res) {
+        Err(EvalError::LimitExceeded {
+            beta_reductions, ..
+        }) => assert_eq!(beta_reductions, 1001),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    let mut ctx = EvalContext::new(Strategy::Need).with_beta_limit(BETA_REDUCTIONS_LIMIT);
+    ctx.eval(&res).unwrap();
+    assert_eq!(ctx.beta_reductions, 23);
+}
+
+#[test]
+fn need_deep_recursion() {
+    // fact 100 and sum 1..=100000, neither is tail recursive
+    let fact = format!(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" {}"#,
+        encode_token(&Token::Int(Int::from(100)))
+    );
+    let eval_res = eval_with(&parse_string(&fact), Strategy::Need);
+    let expected: Int = (1..=100).map(Int::from).product();
+    assert_eq!(eval_res.int(), expected);
+
+    let sum = format!(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I! I! B+ v# B$ v" B- v# I" {}"#,
+        encode_token(&Token::Int(Int::from(100_000)))
+    );
+    let eval_res = eval_with(&parse_string(&sum), Strategy::Need);
+    assert_eq!(eval_res.int_usize(), 100_000 * 100_001 / 2);
+}
+
+#[test]
+fn deep_terms() {
+    // substitution, evaluation and readback of a term deeper than the test
+    // thread's stack allows for recursion (dropping it still recurses)
+    let depth = 10_000;
+    let sum = "B+ v# ".repeat(depth) + "I!";
+    let program = parse_string(&format!("B$ L# {} I\"", sum));
+    let closure = parse_string(&format!("B$ L# L$ {} I\"", sum));
+    for strategy in [Strategy::Name, Strategy::Need] {
+        assert_eq!(eval_with(&program, strategy).int_usize(), depth);
+        let res = eval_with(&closure, strategy);
+        assert_eq!(
+            encode_token(&res),
+            format!("L$ {}I!", "B+ I\" ".repeat(depth))
+        );
+    }
+}
+
+#[test]
+fn capture_avoiding() {
+    for strategy in [Strategy::Name, Strategy::Need] {
+        // (\x. \y. x) y 5 is the free `y`, not 5
+        let res = eval_with(&parse_string(r#"B$ B$ L! L" v! v" I&"#), strategy);
+        assert_eq!(res, Token::Id(1));
+
+        // (\x. \y. x) y must not become \y. y
+        let res = eval_with(&parse_string(r#"B$ L! L" v! v""#), strategy);
+        match res {
+            Token::Abstraction(i, inner) => {
+                assert_ne!(i, 1);
+                assert_eq!(*inner, Token::Id(1));
+            }
+            _ => panic!("Unexpected result: {:?}", res),
+        }
+
+        // renaming must not break the bound occurrences: (\x. \y. y + x) y 5
+        let res = eval_with(&parse_string(r#"B$ L! L" B+ v" v! v""#), strategy);
+        match res {
+            Token::Abstraction(i, inner) => match inner.as_ref() {
+                Token::BinaryOp(BinaryOp::Add, first, second) => {
+                    assert_eq!(**first, Token::Id(i));
+                    assert_eq!(**second, Token::Id(1));
+                }
+                _ => panic!("Unexpected result: {:?}", inner),
+            },
+            _ => panic!("Unexpected result: {:?}", res),
+        }
+
+        // nested binders: (\x. \y. \z. x y z) (y z) has both y and z free
+        let res = eval_with(
+            &parse_string(r#"B$ L! L" L# B$ B$ v! v" v# B$ v" v#"#),
+            strategy,
+        );
+        let free = free_vars(&res);
+        assert_eq!(free, HashSet::from([1, 2]), "res: {:?}", res);
+    }
+}
+
+#[test]
+fn memo_efficiency04() {
+    // naive fibonacci, 40 is out of reach without the cache
+    let input = fs::read_to_string("../efficiency/efficiency04.in").unwrap();
+    let res = parse_string(&input);
+    let mut ctx = EvalContext::new(Strategy::Memo).with_beta_limit(BETA_REDUCTIONS_LIMIT);
+    assert_eq!(ctx.eval(&res).unwrap().int_usize(), 165580141);
+    eprintln!("Memo: {}/{} hits", ctx.memo_hits, ctx.memo_calls);
+    assert_eq!(ctx.memo_calls, 79);
+    assert_eq!(ctx.memo_hits, 38);
+}
+
+#[test]
+fn memo_recursive_readback() {
+    // the partially applied fixpoint function is read back with a Y combinator
+    let input = r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# L$ ? B= v# I! v$ B$ B$ v" B- v# I" B+ v$ I" I$"#;
+    let res = parse_string(input);
+    let memo = eval_with(&res, Strategy::Memo);
+    assert!(matches!(memo, Token::Abstraction(..)));
+    let arg = Rc::new(Token::Int(Int::from(10)));
+    let applied = Token::Application(Rc::new(memo), arg.clone());
+    let expected = Token::Application(Rc::new(res), arg);
+    assert_eq!(eval(&applied), eval(&expected));
+    assert_eq!(eval(&applied).int_usize(), 13);
+}
+
+#[test]
+fn memo_shared_bodies() {
+    use crate::term::Arena;
+
+    // `L% B+ v% I"` is equal to the fixpoint function's body, sharing them
+    // must not make it a memoized call
+    let y = r#"L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v#"#;
+    let input = format!(r#"B+ B$ B$ {} L$ L% B+ v% I" I# B$ L% B+ v% I" I$"#, y);
+    let mut arena = Arena::new();
+    let id = arena.intern_token(&parse_string(&input));
+    let shared = arena.token(id);
+    for strategy in [Strategy::Name, Strategy::Need, Strategy::Memo] {
+        assert_eq!(eval_with(&shared, strategy).int_usize(), 7);
+    }
+
+    // arguments are forced before the call, even when unused
+    let input = format!(r#"B$ B$ {} L$ L% I" B/ I" I!"#, y);
+    let res = parse_string(&input);
+    assert_eq!(eval_with(&res, Strategy::Need).int_usize(), 1);
+    assert!(matches!(
+        try_eval_with(&res, Strategy::Memo),
+        Err(EvalError::InvalidOperand { .. })
+    ));
+}
+
+#[test]
+fn edge_semantics() {
+    let cases = [
+        ("U$ I!", Token::String(b"a".to_vec())),
+        ("U# U$ I!", Token::Int(Int::ZERO)),
+        ("B/ U- I( I#", Token::Int(Int::from(-3))),
+        ("B% U- I( I#", Token::Int(Int::from(-1))),
+        ("B/ I( U- I#", Token::Int(Int::from(-3))),
+        ("B% I( U- I#", Token::Int(Int::from(1))),
+        ("BT I' S4%34", Token::String(b"test".to_vec())),
+        ("BD I' S4%34", Token::String(vec![])),
+        ("BT U- I\" S4%34", Token::String(vec![])),
+        ("BD U- I\" S4%34", Token::String(b"test".to_vec())),
+    ];
+    for strategy in [Strategy::Name, Strategy::Need, Strategy::Memo] {
+        for (input, expected) in &cases {
+            let res = eval_with(&parse_string(input), strategy);
+            assert_eq!(&res, expected, "input: {}", input);
+        }
+        assert!(matches!(
+            try_eval_with(&parse_string("U$ U- I\""), strategy),
+            Err(EvalError::InvalidOperand { .. })
+        ));
+    }
+}
+
+#[test]
+fn language_test() {
+    let input = fs::read_to_string("inputs/language_test.txt").unwrap();
+    let res = parse_string(&input);
+    for strategy in [Strategy::Name, Strategy::Need, Strategy::Memo] {
+        let eval_res = eval_with(&res, strategy);
+        assert_eq!(
+            eval_res.string(),
+            b"Self-check OK, send `solve language_test 4w3s0m3` to claim points for it"
+        );
+    }
 }
@@ -1,28 +1,599 @@
-use std::fs;
-
-use crate::parser::{encode_string, eval, parse_string};
-use anyhow::Result;
-
-pub async fn send_msg(msg: &str) -> Result<()> {
-    let token = dotenv::var("TOKEN")?;
-    eprintln!("Token: {:?}", token);
-
-    let client = reqwest::Client::new();
-    let res = client
-        .post("https://boundvariable.space/communicate")
-        .body(encode_string(msg))
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
-        .await?;
-    eprintln!("Res: {:?}", res);
-    let body = res.text().await?;
-    eprintln!("Body: {:?}", body);
-    // save to file
-    fs::write("inputs/last_response.txt", &body)?;
-    let parsed = parse_string(&body);
-    eprintln!("Parsed: {:?}", parsed);
-    let evaluated = eval(&parsed);
-    eprintln!("Evaluated: {:?}", evaluated);
-
-    Ok(())
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, fs, io,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant};
+
+use crate::cache::{self, Cache, CacheMode};
+use crate::parser::{
+    encode_string, try_parse_string, EvalContext, Strategy, Token, ALPH, BETA_REDUCTIONS_LIMIT,
+};
+use crate::scores::{parse_solved, ScoresDb};
+use crate::types::check_message;
+
+pub const BASE_URL: &str = "https://boundvariable.space";
+
+/// Time allowed for evaluating a response, on top of `BETA_REDUCTIONS_LIMIT`.
+pub const TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// A message to the server, sent as an ICFP string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get(String),
+    Solve { task: String, answer: String },
+    Echo(String),
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Get(task) => write!(f, "get {}", task),
+            Request::Solve { task, answer } => write!(f, "solve {} {}", task, answer),
+            Request::Echo(text) => write!(f, "echo {}", text),
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = ProtocolError;
+
+    fn from_str(msg: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProtocolError::InvalidRequest(msg.to_string());
+        let (command, rest) = msg.split_once(' ').ok_or_else(invalid)?;
+        Ok(match command {
+            "get" => Request::Get(rest.to_string()),
+            "solve" => {
+                let (task, answer) = rest.split_once(' ').ok_or_else(invalid)?;
+                Request::Solve {
+                    task: task.to_string(),
+                    answer: answer.to_string(),
+                }
+            }
+            "echo" => Request::Echo(rest.to_string()),
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    MissingToken,
+    InvalidRequest(String),
+    Http(reqwest::Error),
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// The response is not a valid ICFP program or failed to evaluate.
+    Decode(String),
+    /// Replaying a request that was never recorded.
+    NotCached(String),
+    /// `CACHE` is neither `record` nor `replay`.
+    InvalidCacheMode(String),
+    /// The program to send doesn't evaluate to a string.
+    Type(String),
+    /// The message has a character that ICFP strings can't hold.
+    InvalidChar(char),
+    /// An environment variable that is not a number.
+    InvalidSetting {
+        name: &'static str,
+        value: String,
+    },
+    /// Reading or writing the cache or the submission queue failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::MissingToken => write!(f, "TOKEN is not set"),
+            ProtocolError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
+            ProtocolError::Http(err) => write!(f, "Request failed: {}", err),
+            ProtocolError::Status { status, body } => {
+                write!(f, "Server responded with {}: {}", status, body)
+            }
+            ProtocolError::Decode(message) => write!(f, "Invalid response: {}", message),
+            ProtocolError::NotCached(request) => write!(f, "No recorded response to {}", request),
+            ProtocolError::InvalidCacheMode(mode) => {
+                write!(f, "Invalid CACHE: {}, expected record or replay", mode)
+            }
+            ProtocolError::Type(err) => write!(f, "Ill-typed request: {}", err),
+            ProtocolError::InvalidChar(c) => write!(f, "Can't encode {:?} in a request", c),
+            ProtocolError::InvalidSetting { name, value } => {
+                write!(f, "Invalid {}: {}, expected a number", name, value)
+            }
+            ProtocolError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<reqwest::Error> for ProtocolError {
+    fn from(err: reqwest::Error) -> Self {
+        ProtocolError::Http(err)
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
+
+impl ProtocolError {
+    /// Whether sending the request again may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ProtocolError::Http(_) => true,
+            ProtocolError::Status { status, .. } => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses and evaluates a response within the limits.
+pub fn decode(body: &str) -> Result<Token, ProtocolError> {
+    let decode_err = |err| ProtocolError::Decode(format!("{}", err));
+    let parsed = try_parse_string(body).map_err(decode_err)?;
+    EvalContext::new(Strategy::Need)
+        .with_beta_limit(BETA_REDUCTIONS_LIMIT)
+        .with_time_limit(TIME_LIMIT)
+        .eval(&parsed)
+        .map_err(decode_err)
+}
+
+// `msg` as an ICFP string.
+fn encode_request(msg: &str) -> Result<String, ProtocolError> {
+    match msg.chars().find(|&c| !ALPH.contains(c)) {
+        Some(c) => Err(ProtocolError::InvalidChar(c)),
+        None => Ok(encode_string(msg)),
+    }
+}
+
+// Parses and type checks a program before it is sent.
+fn check_program(program: &str) -> Result<(), ProtocolError> {
+    let parsed =
+        try_parse_string(program).map_err(|err| ProtocolError::InvalidRequest(err.to_string()))?;
+    check_message(&parsed).map_err(|err| ProtocolError::Type(err.to_string()))
+}
+
+pub struct Client {
+    base_url: String,
+    token: String,
+    http: reqwest::Client,
+    cache: Option<Cache>,
+}
+
+// the token is left out so that it doesn't end up in logs
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("base_url", &self.base_url)
+            .field("cache", &self.cache)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    pub fn new(base_url: &str, token: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            http: reqwest::Client::new(),
+            cache: None,
+        }
+    }
+
+    /// Uses `TOKEN`, `BASE_URL` (optional) and the cache settings (see
+    /// `Cache::from_env`) from the environment. Replaying needs no token.
+    pub fn from_env() -> Result<Self, ProtocolError> {
+        let cache = Cache::from_env()?;
+        let token = match dotenv::var("TOKEN") {
+            Ok(token) => token,
+            Err(_) if cache.as_ref().is_some_and(|c| c.mode == CacheMode::Replay) => String::new(),
+            Err(_) => return Err(ProtocolError::MissingToken),
+        };
+        let base_url = dotenv::var("BASE_URL").unwrap_or_else(|_| BASE_URL.to_string());
+        let mut client = Self::new(&base_url, &token);
+        client.cache = cache;
+        Ok(client)
+    }
+
+    #[cfg(test)]
+    pub fn with_cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    #[cfg(test)]
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Sends `msg` and returns the response as ICFP text.
+    pub async fn send_raw(&self, msg: &str) -> Result<String, ProtocolError> {
+        self.send_icfp(&encode_request(msg)?).await
+    }
+
+    /// Sends a program that evaluates to the message, e.g. a compressed
+    /// lambdaman solution. Programs that don't type as a string are rejected.
+    pub async fn send_icfp(&self, program: &str) -> Result<String, ProtocolError> {
+        check_program(program)?;
+        let Some(cache) = &self.cache else {
+            return self.post(program).await;
+        };
+        match cache.mode {
+            CacheMode::Replay => cache
+                .get(program)?
+                .ok_or_else(|| ProtocolError::NotCached(cache::message(program))),
+            CacheMode::Record => {
+                let body = self.post(program).await?;
+                cache.record(program, &body)?;
+                Ok(body)
+            }
+        }
+    }
+
+    async fn post(&self, program: &str) -> Result<String, ProtocolError> {
+        let res = self
+            .http
+            .post(format!("{}/communicate", self.base_url))
+            .body(program.to_string())
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            return Err(ProtocolError::Status { status, body });
+        }
+        Ok(body)
+    }
+
+    pub async fn send(&self, request: &Request) -> Result<Token, ProtocolError> {
+        decode(&self.send_raw(&request.to_string()).await?)
+    }
+
+    pub async fn get(&self, task: &str) -> Result<Token, ProtocolError> {
+        self.send(&Request::Get(task.to_string())).await
+    }
+
+    #[cfg(test)]
+    pub async fn solve(&self, task: &str, answer: &str) -> Result<Token, ProtocolError> {
+        let request = Request::Solve {
+            task: task.to_string(),
+            answer: answer.to_string(),
+        };
+        self.send(&request).await
+    }
+
+    #[cfg(test)]
+    pub async fn echo(&self, text: &str) -> Result<Token, ProtocolError> {
+        self.send(&Request::Echo(text.to_string())).await
+    }
+}
+
+// `solve` requests waiting to be sent, persisted in `<dir>/queue.txt` one
+// request per line so that nothing is lost when the process stops. The decoded
+// responses are kept per task in `<dir>/verdicts.tsv` as `task<TAB>verdict`
+// lines, later lines win. In both files `\`, tabs and newlines are escaped,
+// as answers (e.g. 3d grids) and verdicts can span lines.
+pub struct SubmissionQueue {
+    client: Client,
+    dir: PathBuf,
+    pending: VecDeque<Request>,
+    verdicts: BTreeMap<String, String>,
+    /// Where the scores of the solved tasks are recorded.
+    pub scores: Option<ScoresDb>,
+    /// The minimum time between two requests.
+    pub interval: Duration,
+    /// How many times a transient failure is retried.
+    pub retries: usize,
+    /// The wait before the first retry, doubled for every next one.
+    pub backoff: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl SubmissionQueue {
+    /// Loads the pending submissions and the verdicts from `dir`.
+    pub fn open(client: Client, dir: impl Into<PathBuf>) -> Result<Self, ProtocolError> {
+        let dir = dir.into();
+        let read = |name| match fs::read_to_string(dir.join(name)) {
+            Ok(text) => Ok(text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(err),
+        };
+        let pending = read("queue.txt")?
+            .lines()
+            .map(|line| unescape(line).parse())
+            .collect::<Result<_, _>>()?;
+        let verdicts = read("verdicts.tsv")?
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(task, verdict)| (unescape(task), unescape(verdict)))
+            .collect();
+        Ok(Self {
+            client,
+            dir,
+            pending,
+            verdicts,
+            scores: None,
+            interval: Duration::from_secs(1),
+            retries: 5,
+            backoff: Duration::from_secs(1),
+            last_sent: None,
+        })
+    }
+
+    /// `open` with the rate limit from `SUBMIT_INTERVAL_MS`, `SUBMIT_RETRIES`
+    /// and `SUBMIT_BACKOFF_MS` when they are set.
+    pub fn from_env(client: Client, dir: impl Into<PathBuf>) -> Result<Self, ProtocolError> {
+        let setting = |name| match dotenv::var(name) {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => Err(ProtocolError::InvalidSetting { name, value }),
+            },
+            Err(_) => Ok(None),
+        };
+        let mut queue = Self::open(client, dir)?;
+        if let Some(ms) = setting("SUBMIT_INTERVAL_MS")? {
+            queue = queue.with_interval(Duration::from_millis(ms));
+        }
+        let retries = setting("SUBMIT_RETRIES")?.map_or(queue.retries, |n| n as usize);
+        let backoff = setting("SUBMIT_BACKOFF_MS")?.map_or(queue.backoff, Duration::from_millis);
+        Ok(queue.with_retries(retries, backoff))
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_scores(mut self, scores: ScoresDb) -> Self {
+        self.scores = Some(scores);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: usize, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &Request> {
+        self.pending.iter()
+    }
+
+    /// The last response to a `solve` of `task`.
+    pub fn verdict(&self, task: &str) -> Option<&str> {
+        self.verdicts.get(task).map(|verdict| verdict.as_str())
+    }
+
+    /// Queues a submission, checked like every program sent.
+    pub fn push(&mut self, task: &str, answer: &str) -> Result<(), ProtocolError> {
+        let request = Request::Solve {
+            task: task.to_string(),
+            answer: answer.to_string(),
+        };
+        check_program(&encode_request(&request.to_string())?)?;
+        self.pending.push_back(request);
+        self.save_pending()
+    }
+
+    /// Sends the pending submissions in order. A submission the server rejects
+    /// gets the error as its verdict, the queue stops at one that still fails
+    /// after the retries and keeps it for the next time.
+    pub async fn flush(&mut self) -> Result<(), ProtocolError> {
+        while let Some(request) = self.pending.front().cloned() {
+            let Request::Solve { task, .. } = &request else {
+                unreachable!("only solve requests are queued");
+            };
+            let verdict = match self.send_with_retries(&request).await {
+                Ok(Token::String(verdict)) => String::from_utf8_lossy(&verdict).into_owned(),
+                Ok(token) => format!("{:?}", token),
+                Err(err) if err.is_transient() => return Err(err),
+                Err(err) => format!("Error: {}", err),
+            };
+            self.record_verdict(task, &verdict)?;
+            // tasks that are only right or wrong have no score to beat
+            let solved = parse_solved(&verdict).and_then(|s| Some((s.task, s.score?)));
+            if let (Some(scores), Some((task, score))) = (&mut self.scores, solved) {
+                scores.record(&task, score)?;
+            }
+            self.pending.pop_front();
+            self.save_pending()?;
+        }
+        Ok(())
+    }
+
+    async fn send_with_retries(&mut self, request: &Request) -> Result<Token, ProtocolError> {
+        let mut backoff = self.backoff;
+        for attempt in 0.. {
+            if let Some(last_sent) = self.last_sent {
+                tokio::time::sleep_until(last_sent + self.interval).await;
+            }
+            self.last_sent = Some(Instant::now());
+            match self.client.send(request).await {
+                Err(err) if err.is_transient() && attempt < self.retries => {
+                    eprintln!("{}, retrying in {:?}", err, backoff);
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                res => return res,
+            }
+        }
+        unreachable!()
+    }
+
+    fn record_verdict(&mut self, task: &str, verdict: &str) -> Result<(), ProtocolError> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("verdicts.tsv"))?;
+        io::Write::write_all(
+            &mut file,
+            format!("{}\t{}\n", escape(task), escape(verdict)).as_bytes(),
+        )?;
+        self.verdicts.insert(task.to_string(), verdict.to_string());
+        Ok(())
+    }
+
+    fn save_pending(&self) -> Result<(), ProtocolError> {
+        fs::create_dir_all(&self.dir)?;
+        let text: String = self
+            .pending
+            .iter()
+            .map(|r| format!("{}\n", escape(&r.to_string())))
+            .collect();
+        // written whole and renamed, so that a crash leaves the old queue
+        let tmp = self.dir.join("queue.txt.tmp");
+        fs::write(&tmp, text)?;
+        Ok(fs::rename(tmp, self.dir.join("queue.txt"))?)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut res = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => res.push('\t'),
+            Some('n') => res.push('\n'),
+            Some(c) => res.push(c),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+#[test]
+fn requests() {
+    let request: Request = "solve spaceship1 31619".parse().unwrap();
+    assert_eq!(
+        request,
+        Request::Solve {
+            task: "spaceship1".to_string(),
+            answer: "31619".to_string()
+        }
+    );
+    assert_eq!(request.to_string(), "solve spaceship1 31619");
+    assert_eq!(
+        "get lambdaman 4".parse::<Request>().unwrap(),
+        Request::Get("lambdaman 4".to_string())
+    );
+    assert!("solve spaceship1".parse::<Request>().is_err());
+    assert!("hello".parse::<Request>().is_err());
+
+    let client = Client::new(BASE_URL, "secret").with_base_url("http://localhost:8000/");
+    assert_eq!(client.base_url(), "http://localhost:8000");
+    assert!(!format!("{:?}", client).contains("secret"));
+
+    assert_eq!(
+        decode("B. SB%,,/ S}Q/2,$_").unwrap(),
+        Token::String(b"Hello World!".to_vec())
+    );
+    assert!(matches!(decode("B. SB%,,/"), Err(ProtocolError::Decode(_))));
+    let omega = r#"B$ L! B$ v! v! L! B$ v! v!"#;
+    assert!(matches!(decode(omega), Err(ProtocolError::Decode(_))));
+    assert!(matches!(
+        encode_request("echo é"),
+        Err(ProtocolError::InvalidChar('é'))
+    ));
+}
+
+#[tokio::test]
+async fn submission_queue() {
+    use crate::{mock_server::MockServer, workspace::Workspace};
+
+    let dir = std::env::temp_dir().join(format!("queue_{}", std::process::id()));
+    let url = MockServer::new(Workspace::default())
+        .with_failures(2)
+        .spawn()
+        .await
+        .unwrap();
+    let open = || {
+        SubmissionQueue::open(Client::new(&url, ""), &dir)
+            .unwrap()
+            .with_interval(Duration::from_millis(50))
+            .with_retries(2, Duration::from_millis(10))
+    };
+    let scores_path = dir.join("scores.tsv");
+    let mut queue = open().with_scores(ScoresDb::open(&scores_path).unwrap());
+    queue.push("spaceship1", "31619").unwrap();
+    queue.push("spaceship1", "3161").unwrap();
+    queue.push("lambdaman1", "UDLLLDURRRRRURR").unwrap();
+    assert_eq!(open().pending().count(), 3);
+    let res = Client::new(&url, "").send_icfp("B+ I\" I\"").await;
+    assert!(matches!(res, Err(ProtocolError::Type(_))));
+
+    let start = Instant::now();
+    queue.flush().await.unwrap();
+    // two failed attempts and three submissions
+    assert!(start.elapsed() >= Duration::from_millis(4 * 50));
+    assert_eq!(queue.pending().count(), 0);
+    let queue = open();
+    assert_eq!(queue.pending().count(), 0);
+    assert_eq!(
+        queue.verdict("spaceship1"),
+        Some("Your solution missed 1 squares")
+    );
+    assert_eq!(
+        queue.verdict("lambdaman1"),
+        Some("Correct, you solved lambdaman1 with a score of 33!")
+    );
+    let scores = ScoresDb::open(&scores_path).unwrap();
+    assert_eq!(scores.get("spaceship1").unwrap().score, 5);
+    assert_eq!(scores.get("lambdaman1").unwrap().score, 33);
+
+    // the failures outlast the retries, the submission stays queued
+    let url = MockServer::new(Workspace::default())
+        .with_failures(3)
+        .spawn()
+        .await
+        .unwrap();
+    let mut queue = SubmissionQueue::open(Client::new(&url, ""), &dir)
+        .unwrap()
+        .with_retries(2, Duration::from_millis(10));
+    queue.push("spaceship1", "31619").unwrap();
+    let err = queue.flush().await.unwrap_err();
+    assert!(matches!(err, ProtocolError::Status { status, .. } if status == 503));
+    assert_eq!(open().pending().count(), 1);
+
+    let grid = ". A .\n1 * S\n. B =\n";
+    open().push("3d1", grid).unwrap();
+    let queue = open();
+    assert_eq!(queue.pending().count(), 2);
+    assert_eq!(
+        queue.pending().last(),
+        Some(&Request::Solve {
+            task: "3d1".to_string(),
+            answer: grid.to_string()
+        })
+    );
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(unescape(&escape("a\tb\\n\nc")), "a\tb\\n\nc");
 }
@@ -0,0 +1,301 @@
+use std::{
+    io::{self, BufRead, Write},
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    compiler::compile_with,
+    decompiler::decompile,
+    parser::{encode_token, free_vars, substitute, try_parse_string, EvalContext, Strategy, Token},
+    protocol::{Client, Request, BASE_URL},
+    types::infer,
+};
+
+// Reads ICFP text or the language of the decompiler line by line and prints
+// the results. `let name = value` without `in` defines a binding that the
+// following lines can use, bindings must be closed terms. A line is read as
+// ICFP when it parses as a closed term, then as source, then as ICFP with
+// free variables: `v1` is the binding named `v1` if there is one, and not the
+// ICFP variable 16.
+
+const HELP: &str = "\
+<expr>                 evaluate ICFP text or a decompiled expression
+let [rec] name = <expr>  bind the value of the expression to `name`
+:strategy name|need|memo
+:limit <beta reductions>|none
+:time <seconds>|none
+:bindings              list the bindings
+:encode <expr>         print the expression as ICFP
+:type <expr>           infer the type of the expression
+:server [url]          show or set the server `:send` talks to
+:send <message>        send `get`, `solve` or `echo` and show the response
+:help
+:quit";
+
+pub struct Repl {
+    bindings: Vec<(String, Token)>,
+    pub strategy: Strategy,
+    pub beta_limit: Option<usize>,
+    pub time_limit: Option<Duration>,
+    pub base_url: String,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            bindings: vec![],
+            strategy: Strategy::Need,
+            beta_limit: None,
+            time_limit: None,
+            base_url: dotenv::var("BASE_URL").unwrap_or_else(|_| BASE_URL.to_string()),
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut lines = io::stdin().lock().lines();
+        loop {
+            print!("> ");
+            io::stdout().flush()?;
+            let Some(line) = lines.next() else {
+                return Ok(());
+            };
+            let line = line?;
+            let line = line.trim();
+            let res = if let Some(msg) = line.strip_prefix(":send") {
+                self.send(msg.trim()).await
+            } else if line == ":quit" {
+                return Ok(());
+            } else {
+                self.input(line)
+            };
+            match res {
+                Ok(output) if output.is_empty() => {}
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+
+    async fn send(&mut self, msg: &str) -> Result<String> {
+        let request: Request = msg.parse()?;
+        let client = Client::from_env()?.with_base_url(&self.base_url);
+        let res = client.send(&request).await?;
+        Ok(decompile(&res))
+    }
+
+    /// Handles a line other than `:send` and `:quit`, returns what to print.
+    pub fn input(&mut self, line: &str) -> Result<String> {
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        match command {
+            "" => Ok(String::new()),
+            ":help" => Ok(HELP.to_string()),
+            ":strategy" => {
+                self.strategy = match arg {
+                    "name" => Strategy::Name,
+                    "need" => Strategy::Need,
+                    "memo" => Strategy::Memo,
+                    _ => bail!("Unknown strategy: {}", arg),
+                };
+                Ok(String::new())
+            }
+            ":limit" => {
+                self.beta_limit = match arg {
+                    "none" => None,
+                    _ => Some(arg.parse()?),
+                };
+                Ok(String::new())
+            }
+            ":time" => {
+                self.time_limit = match arg {
+                    "none" => None,
+                    _ => Some(Duration::from_secs_f64(arg.parse()?)),
+                };
+                Ok(String::new())
+            }
+            ":bindings" => Ok(self
+                .bindings
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, decompile(value)))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ":encode" => Ok(encode_token(&self.term(arg)?)),
+            ":type" => match infer(&self.term(arg)?) {
+                Ok(t) => Ok(t.to_string()),
+                Err(err) => bail!("{}", err),
+            },
+            ":server" if arg.is_empty() => Ok(self.base_url.clone()),
+            ":server" => {
+                self.base_url = arg.to_string();
+                Ok(String::new())
+            }
+            _ if command.starts_with(':') => bail!("Unknown command: {}, see :help", command),
+            _ => match self.definition(line) {
+                Some((name, term)) => {
+                    let term = term?;
+                    let free = free_vars(&term);
+                    if !free.is_empty() {
+                        let mut free: Vec<_> = free.into_iter().collect();
+                        free.sort_unstable();
+                        let free: Vec<_> = free.iter().map(|var| format!("v{}", var)).collect();
+                        bail!("{} has free variables: {}", name, free.join(", "));
+                    }
+                    // the read back closures can be much larger than the term
+                    let value = match self.run_eval(&term)?.0 {
+                        value @ (Token::Bool(_) | Token::Int(_) | Token::String(_)) => value,
+                        _ => term,
+                    };
+                    let output = format!("{} = {}", name, decompile(&value));
+                    match self.bindings.iter_mut().find(|(n, _)| *n == name) {
+                        Some(binding) => binding.1 = value,
+                        None => self.bindings.push((name, value)),
+                    }
+                    Ok(output)
+                }
+                None => {
+                    let term = self.term(line)?;
+                    self.eval(term)
+                }
+            },
+        }
+    }
+
+    fn eval(&self, term: Token) -> Result<String> {
+        let warning = match infer(&term) {
+            Ok(_) => String::new(),
+            Err(err) => format!("Warning: {}\n", err),
+        };
+        let (value, ctx) = match self.run_eval(&term) {
+            Ok(res) => res,
+            Err(err) => bail!("{}{}", warning, err),
+        };
+        Ok(format!(
+            "{}{}\nBeta reductions: {}. Time: {:?}",
+            warning,
+            decompile(&value),
+            ctx.beta_reductions,
+            ctx.elapsed
+        ))
+    }
+
+    fn run_eval(&self, term: &Token) -> Result<(Token, EvalContext)> {
+        let mut ctx = EvalContext::new(self.strategy);
+        ctx.beta_limit = self.beta_limit;
+        ctx.time_limit = self.time_limit;
+        let value = ctx.eval(term).map_err(|err| anyhow!("{}", err))?;
+        Ok((value, ctx))
+    }
+
+    // Closed ICFP text, source using the bindings, which are substituted in,
+    // or ICFP text with free variables, in this order.
+    fn term(&self, source: &str) -> Result<Token> {
+        let icfp = try_parse_string(source).ok();
+        if let Some(token) = icfp.as_ref().filter(|token| free_vars(token).is_empty()) {
+            return Ok(token.clone());
+        }
+        let names: Vec<_> = self.bindings.iter().map(|(name, _)| name.clone()).collect();
+        let compiled = match (compile_with(source, &names), icfp) {
+            (Ok(compiled), _) => compiled,
+            (Err(_), Some(token)) => return Ok(token),
+            (Err(err), None) => return Err(err.into()),
+        };
+        let mut res = Rc::new(compiled);
+        // values are closed, so the order doesn't matter
+        for (var, (_, value)) in self.bindings.iter().enumerate() {
+            res = substitute(&res, var, Rc::new(value.clone()));
+        }
+        Ok(res.as_ref().clone())
+    }
+
+    // `let [rec] name params = value` with no `in`, returns the name and the
+    // value. `None` when the line is an expression.
+    fn definition(&self, line: &str) -> Option<(String, Result<Token>)> {
+        let rest = line.strip_prefix("let ")?.trim_start();
+        let rest = rest.strip_prefix("rec ").unwrap_or(rest).trim_start();
+        let end = rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let name = rest[..end].to_string();
+        if name.is_empty() || self.term(line).is_ok() {
+            return None;
+        }
+        if let Some(icfp) = rest[end..].trim_start().strip_prefix('=') {
+            if let Ok(token) = try_parse_string(icfp) {
+                return Some((name, Ok(token)));
+            }
+        }
+        let term = self
+            .term(&format!("{} in {}", line, name))
+            .map_err(|_| self.term(line).unwrap_err());
+        Some((name, term))
+    }
+}
+
+pub async fn repl() -> Result<()> {
+    Repl::new().run().await
+}
+
+#[test]
+fn repl_bindings() {
+    let mut repl = Repl::new();
+    let mut result = |line: &str| {
+        let output = repl.input(line).unwrap_or_else(|err| err.to_string());
+        output.lines().next().unwrap_or("").to_string()
+    };
+    assert_eq!(result("B+ I# I$"), "5");
+    assert_eq!(result("1 + 2 * 3"), "7");
+    assert_eq!(
+        result("let rec fact n = if n == 1 then 1 else n * fact (n - 1)"),
+        "fact = let rec v0 v1 = if v1 == 1 then 1 else v1 * v0 (v1 - 1) in v0"
+    );
+    assert_eq!(result("let x = 5"), "x = 5");
+    assert_eq!(result("fact x"), "120");
+    assert_eq!(result("let x = 3 in fact x"), "6");
+    assert_eq!(result("let x = B* I# I$"), "x = 6");
+    assert_eq!(result("fact x"), "720");
+    assert_eq!(result(":encode x + 1"), "B+ I' I\"");
+    assert_eq!(result(":type fact"), "int -> int");
+    assert_eq!(
+        result("1 + (fun x -> x)"),
+        "Warning: Type error at token 2: expected int, got 'a -> 'a in fun v2 -> v2"
+    );
+    assert!(result("let y = fact").starts_with("y = let rec "));
+    assert_eq!(result("y 4 == fact 4"), "true");
+
+    assert_eq!(result(":strategy memo"), "");
+    assert_eq!(result("fact 10"), "3628800");
+    assert_eq!(result(":limit 3"), "");
+    assert!(result("fact 10").starts_with("Evaluation limit exceeded after 4 beta reductions"));
+    assert_eq!(result(":limit none"), "");
+
+    assert_eq!(
+        result("z + 1"),
+        "Compile error at 1:1: Unknown variable, got Ident(\"z\")"
+    );
+    assert_eq!(
+        result("let f n = n +"),
+        "Compile error at 1:14: Expected an expression, got end of input"
+    );
+    assert_eq!(result(":strategy fast"), "Unknown strategy: fast");
+
+    // names from the decompiler's output are bindings, not ICFP variables
+    assert_eq!(result("let v1 = 4"), "v1 = 4");
+    assert_eq!(result("v1"), "4");
+    assert_eq!(result("fact v1"), "24");
+    assert_eq!(result("let y = B+ v# I\""), "y has free variables: v2");
+    assert_eq!(result("v1 + 1"), "5");
+    assert_eq!(
+        result(":bindings"),
+        "fact = let rec v0 v1 = if v1 == 1 then 1 else v1 * v0 (v1 - 1) in v0"
+    );
+    assert_eq!(repl.bindings.len(), 4);
+}
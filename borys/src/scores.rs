@@ -0,0 +1,293 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::workspace::{Workspace, FAMILIES};
+
+// Scores from the server's responses. Lower scores are better in every
+// category. `ScoresDb` keeps the best score we got per task in a file of
+// `task<TAB>score<TAB>best<TAB>time` lines, where `best` is the best score of
+// all teams when known (`-` otherwise) and `time` is in unix seconds. The
+// last scoreboard is saved with one tab separated line per row.
+
+/// "Correct, you solved spaceship1 with a score of 5!"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Solved {
+    pub task: String,
+    /// Not given for tasks that are only right or wrong.
+    pub score: Option<u64>,
+}
+
+pub fn parse_solved(response: &str) -> Option<Solved> {
+    let rest = response.trim().strip_prefix("Correct, you solved ")?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(rest.len());
+    let score = rest[end..]
+        .strip_prefix(" with a score of ")
+        .and_then(leading_number);
+    Some(Solved {
+        task: rest[..end].to_string(),
+        score,
+    })
+}
+
+/// A line of a task listing such as `get spaceship`:
+/// "* [spaceship1] Your score: 5. Best score: 5."
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskScore {
+    pub task: String,
+    pub yours: Option<u64>,
+    pub best: Option<u64>,
+}
+
+pub fn parse_task_scores(text: &str) -> Vec<TaskScore> {
+    text.lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once('[')?;
+            let (task, rest) = rest.split_once(']')?;
+            let score = |label: &str| {
+                let (_, score) = rest.split_once(label)?;
+                leading_number(score.trim_start())
+            };
+            Some(TaskScore {
+                task: task.to_string(),
+                yours: score("Your score:"),
+                best: score("Best score:"),
+            })
+        })
+        .collect()
+}
+
+/// The markdown table of `get scoreboard`, without the separator line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scoreboard {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Scoreboard {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text: String = [&self.header]
+            .into_iter()
+            .chain(&self.rows)
+            .map(|row| format!("{}\n", row.join("\t")))
+            .collect();
+        fs::write(path, text)
+    }
+}
+
+pub fn parse_scoreboard(text: &str) -> Option<Scoreboard> {
+    let mut rows = text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with('|'))
+        .map(|line| {
+            line.trim_matches('|')
+                .split('|')
+                .map(|cell| cell.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|cells| {
+            !cells
+                .iter()
+                .all(|cell| cell.chars().all(|c| c == '-' || c == ':'))
+        });
+    let header = rows.next()?;
+    Some(Scoreboard {
+        header,
+        rows: rows.collect(),
+    })
+}
+
+/// Keeps what the response to `get <page>` tells about the scores: a task
+/// listing updates the scores database, the scoreboard is saved.
+pub fn save_page(ws: &Workspace, page: &str, text: &str) -> io::Result<()> {
+    if page == "scoreboard" {
+        if let Some(board) = parse_scoreboard(text) {
+            fs::create_dir_all(ws.root())?;
+            board.save(&ws.scoreboard())?;
+        }
+    } else if FAMILIES.contains(&page) {
+        ScoresDb::open(ws.scores())?.update(&parse_task_scores(text))?;
+    }
+    Ok(())
+}
+
+fn leading_number(s: &str) -> Option<u64> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s[..end].parse().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub score: u64,
+    pub best: Option<u64>,
+    pub time: u64,
+}
+
+#[derive(Debug)]
+pub struct ScoresDb {
+    path: PathBuf,
+    records: BTreeMap<String, Record>,
+}
+
+impl ScoresDb {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid line in {}: {}", path.display(), line),
+            )
+        };
+        let mut records = BTreeMap::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let fields: Vec<_> = line.split('\t').collect();
+            let [task, score, best, time] = fields[..] else {
+                return Err(invalid(line));
+            };
+            let record = Record {
+                score: score.parse().map_err(|_| invalid(line))?,
+                best: match best {
+                    "-" => None,
+                    best => Some(best.parse().map_err(|_| invalid(line))?),
+                },
+                time: time.parse().map_err(|_| invalid(line))?,
+            };
+            records.insert(task.to_string(), record);
+        }
+        Ok(Self { path, records })
+    }
+
+    pub fn get(&self, task: &str) -> Option<&Record> {
+        self.records.get(task)
+    }
+
+    /// Whether `score` beats the recorded score, true for unsolved tasks.
+    pub fn is_better(&self, task: &str, score: u64) -> bool {
+        self.get(task).is_none_or(|record| score < record.score)
+    }
+
+    /// Records `score` if it is better, returns whether it was.
+    pub fn record(&mut self, task: &str, score: u64) -> io::Result<bool> {
+        if !self.is_better(task, score) {
+            return Ok(false);
+        }
+        let best = self.get(task).and_then(|record| record.best);
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let record = Record { score, best, time };
+        self.records.insert(task.to_string(), record);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Updates the best scores of all teams and our scores from a task
+    /// listing.
+    pub fn update(&mut self, scores: &[TaskScore]) -> io::Result<()> {
+        for task_score in scores {
+            if let Some(score) = task_score.yours {
+                self.record(&task_score.task, score)?;
+            }
+            if let Some(record) = self.records.get_mut(&task_score.task) {
+                record.best = task_score.best.or(record.best);
+            }
+        }
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let text: String = self
+            .records
+            .iter()
+            .map(|(task, record)| {
+                let best = record.best.map_or("-".to_string(), |best| best.to_string());
+                format!("{}\t{}\t{}\t{}\n", task, record.score, best, record.time)
+            })
+            .collect();
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, text)
+    }
+}
+
+#[test]
+fn scores() {
+    assert_eq!(
+        parse_solved("Correct, you solved spaceship12 with a score of 5193!"),
+        Some(Solved {
+            task: "spaceship12".to_string(),
+            score: Some(5193)
+        })
+    );
+    assert_eq!(
+        parse_solved("Correct, you solved efficiency3!")
+            .unwrap()
+            .score,
+        None
+    );
+    assert_eq!(parse_solved("Your solution missed 1 squares"), None);
+
+    let listing = "\
+The following tasks are available:
+* [lambdaman1] Your score: 33. Best score: 33.
+* [lambdaman2] Best score: 44.
+* [lambdaman3]
+";
+    let tasks = parse_task_scores(listing);
+    assert_eq!(tasks.len(), 3);
+    assert_eq!((tasks[0].yours, tasks[0].best), (Some(33), Some(33)));
+    assert_eq!((tasks[1].yours, tasks[1].best), (None, Some(44)));
+    assert_eq!((tasks[2].yours, tasks[2].best), (None, None));
+
+    let board = parse_scoreboard(
+        "| # | team name | lambdaman |\n|---|---|---:|\n| 1 | Unagi | 112 |\n| 2 | us | 150 |\n",
+    )
+    .unwrap();
+    assert_eq!(board.header, ["#", "team name", "lambdaman"]);
+    assert_eq!(board.rows.len(), 2);
+    assert_eq!(board.rows[1], ["2", "us", "150"]);
+
+    let path = std::env::temp_dir().join(format!("scores_{}.tsv", std::process::id()));
+    let mut db = ScoresDb::open(&path).unwrap();
+    assert!(db.is_better("spaceship1", 100));
+    assert!(db.record("spaceship1", 100).unwrap());
+    assert!(!db.record("spaceship1", 120).unwrap());
+    db.update(&tasks).unwrap();
+    let db = ScoresDb::open(&path).unwrap();
+    assert_eq!(db.get("spaceship1").unwrap().score, 100);
+    assert_eq!(db.get("lambdaman1").unwrap().best, Some(33));
+    assert!(db.get("lambdaman2").is_none());
+    assert!(!db.is_better("lambdaman1", 33));
+    fs::remove_file(&path).unwrap();
+
+    let ws = Workspace::new(std::env::temp_dir().join(format!("scores_{}", std::process::id())));
+    save_page(&ws, "lambdaman", listing).unwrap();
+    save_page(
+        &ws,
+        "scoreboard",
+        "| # | team name |\n|---|---|\n| 1 | us |\n",
+    )
+    .unwrap();
+    // not a listing
+    save_page(&ws, "lambdaman2", "* [lambdaman2] Your score: 1.").unwrap();
+    let db = ScoresDb::open(ws.scores()).unwrap();
+    assert_eq!(db.get("lambdaman1").unwrap().score, 33);
+    assert!(db.get("lambdaman2").is_none());
+    assert_eq!(
+        fs::read_to_string(ws.scoreboard()).unwrap(),
+        "#\tteam name\n1\tus\n"
+    );
+    fs::remove_dir_all(ws.root()).unwrap();
+}
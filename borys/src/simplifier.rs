@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use crate::{
+    parser::{substitute, try_eval, Token},
+    term::{Arena, Term, TermId},
+};
+
+// Static simplification of ICFP terms, applied until nothing changes:
+// - operators on literals are folded, unless evaluating them fails
+// - `if` with a literal condition is replaced by the taken branch
+// - `B$ (L x. body) arg` is beta-reduced when it doesn't duplicate work or
+//   grow the program: `arg` is unused, used once outside of any lambda, or is
+//   a value (literal, variable, abstraction) used once; literals and
+//   variables are inlined any number of times; the copies of `arg` must not
+//   take more bytes than the application they replace
+// - `L x. B$ f x` becomes `f` when `x` is not free in `f`
+//
+// Every rewrite removes nodes, so this always terminates. Terms are interned
+// in an `Arena`: a subterm repeated in the program is simplified once, and a
+// round changed nothing when it returns the same term.
+
+pub fn simplify(token: &Token) -> Token {
+    let mut simplifier = Simplifier {
+        arena: Arena::new(),
+        done: HashMap::new(),
+    };
+    let mut id = simplifier.arena.intern_token(token);
+    loop {
+        let res = simplifier.simplify(id);
+        if res == id {
+            return simplifier.arena.token(id).as_ref().clone();
+        }
+        id = res;
+    }
+}
+
+struct Simplifier {
+    arena: Arena,
+    // the result of one round for every term seen
+    done: HashMap<TermId, TermId>,
+}
+
+impl Simplifier {
+    fn is_literal(&self, id: TermId) -> bool {
+        matches!(
+            self.arena[id],
+            Term::Bool(_) | Term::Int(_) | Term::String(_)
+        )
+    }
+
+    // Free occurrences of `var` in the term and whether any of them is inside
+    // an abstraction.
+    fn occurrences(&self, id: TermId, var: usize) -> (usize, bool) {
+        let mut count = 0;
+        let mut under_lambda = false;
+        let mut stack = vec![(id, false)];
+        while let Some((id, in_lambda)) = stack.pop() {
+            if self.arena.free_vars(id).binary_search(&var).is_err() {
+                continue;
+            }
+            match &self.arena[id] {
+                Term::Id(_) => {
+                    count += 1;
+                    under_lambda |= in_lambda;
+                }
+                Term::Abstraction(_, inner) => stack.push((*inner, true)),
+                term => stack.extend(term.children().into_iter().map(|c| (c, in_lambda))),
+            }
+        }
+        (count, under_lambda)
+    }
+
+    // Whether `B$ f arg` can be replaced by the body of `f` with `arg`
+    // substituted, `f` being `L var. body`.
+    fn can_inline(&self, f: TermId, body: TermId, var: usize, arg: TermId) -> bool {
+        let (count, under_lambda) = self.occurrences(body, var);
+        let arg_size = self.arena.size(arg);
+        if count.saturating_mul(arg_size) > self.arena.size(f).saturating_add(arg_size + 2) {
+            return false;
+        }
+        match self.arena[arg] {
+            _ if count == 0 => true,
+            Term::Id(_) | Term::Bool(_) | Term::Int(_) | Term::String(_) => true,
+            Term::Abstraction(..) => count == 1,
+            _ => count == 1 && !under_lambda,
+        }
+    }
+
+    // The literal the operator evaluates to, `id` itself if that fails.
+    fn fold(&mut self, id: TermId) -> TermId {
+        match try_eval(&self.arena.token(id)) {
+            Ok(folded) => self.arena.intern_token(&folded),
+            Err(_) => id,
+        }
+    }
+
+    // One round over the term, on an explicit stack as programs can be deeper
+    // than the Rust stack.
+    fn simplify(&mut self, id: TermId) -> TermId {
+        let mut tasks = vec![Task::Visit(id)];
+        let mut results: Vec<TermId> = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(id) => {
+                    if let Some(&res) = self.done.get(&id) {
+                        results.push(res);
+                        continue;
+                    }
+                    match self.arena[id] {
+                        Term::Bool(_) | Term::Int(_) | Term::String(_) | Term::Id(_) => {
+                            self.done.insert(id, id);
+                            results.push(id);
+                        }
+                        Term::If(cond, ..) => {
+                            tasks.push(Task::Branch(id));
+                            tasks.push(Task::Visit(cond));
+                        }
+                        ref term => {
+                            tasks.push(Task::Build(id));
+                            tasks.extend(term.children().into_iter().rev().map(Task::Visit));
+                        }
+                    }
+                }
+                Task::Branch(id) => {
+                    let Term::If(_, first, second) = self.arena[id] else {
+                        unreachable!();
+                    };
+                    let cond = *results.last().unwrap();
+                    if let Term::Bool(b) = self.arena[cond] {
+                        results.pop();
+                        tasks.push(Task::Done(id));
+                        tasks.push(Task::Visit(if b { first } else { second }));
+                    } else {
+                        tasks.push(Task::Build(id));
+                        tasks.push(Task::Visit(second));
+                        tasks.push(Task::Visit(first));
+                    }
+                }
+                Task::Build(id) => {
+                    let count = self.arena[id].children().len();
+                    let children = results.split_off(results.len() - count);
+                    let res = self.build(id, &children);
+                    self.done.insert(id, res);
+                    results.push(res);
+                }
+                Task::Done(id) => {
+                    self.done.insert(id, *results.last().unwrap());
+                }
+            }
+        }
+        results.pop().unwrap()
+    }
+
+    // The rewritten `id` given its simplified children.
+    fn build(&mut self, id: TermId, children: &[TermId]) -> TermId {
+        match (self.arena[id].clone(), children) {
+            (Term::UnaryOp(op, _), &[inner]) => {
+                let res = self.arena.intern(Term::UnaryOp(op, inner));
+                if self.is_literal(inner) {
+                    self.fold(res)
+                } else {
+                    res
+                }
+            }
+            (Term::BinaryOp(op, ..), &[first, second]) => {
+                let res = self.arena.intern(Term::BinaryOp(op, first, second));
+                if self.is_literal(first) && self.is_literal(second) {
+                    self.fold(res)
+                } else {
+                    res
+                }
+            }
+            (Term::If(..), &[cond, first, second]) => {
+                self.arena.intern(Term::If(cond, first, second))
+            }
+            (Term::Application(..), &[f, arg]) => {
+                let lambda = match self.arena[f] {
+                    Term::Abstraction(var, body) => Some((var, body)),
+                    _ => None,
+                };
+                match lambda {
+                    Some((var, body)) if self.can_inline(f, body, var, arg) => {
+                        // the result is simplified again in the next round
+                        let body = self.arena.token(body);
+                        let arg = self.arena.token(arg);
+                        self.arena.intern_token(&substitute(&body, var, arg))
+                    }
+                    _ => self.arena.intern(Term::Application(f, arg)),
+                }
+            }
+            (Term::Abstraction(var, _), &[body]) => {
+                let eta = match self.arena[body] {
+                    Term::Application(f, arg) if self.arena[arg] == Term::Id(var) => Some(f),
+                    _ => None,
+                };
+                match eta {
+                    Some(f) if self.arena.free_vars(f).binary_search(&var).is_err() => f,
+                    _ => self.arena.intern(Term::Abstraction(var, body)),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+enum Task {
+    Visit(TermId),
+    // the simplified children of the term are on top of the results
+    Build(TermId),
+    // the simplified condition of the `if` is on top of the results
+    Branch(TermId),
+    // the result of the term is on top of the results
+    Done(TermId),
+}
+
+#[test]
+fn simplify_simple() {
+    use crate::parser::{encode_token, parse_string};
+
+    let simplified = |input: &str| encode_token(&simplify(&parse_string(input)));
+    assert_eq!(simplified("B+ I# I$"), "I&");
+    assert_eq!(simplified("? B< I\" I# S! S\""), "S!");
+    assert_eq!(simplified("B$ L# B+ v# v# I$"), "I'");
+    assert_eq!(simplified("L# B$ v\" v#"), "v\"");
+    assert_eq!(simplified("U- T"), "U- T");
+    assert_eq!(simplified("B/ I# I!"), "B/ I# I!");
+    assert_eq!(simplified("B$ L# I$ B/ I# I!"), "I$");
+    // inlining into the lambda would evaluate the argument on every call
+    assert_eq!(simplified("B$ L# L$ v# B$ v\" I#"), "B$ L# L$ v# B$ v\" I#");
+    assert_eq!(simplified("B$ L# L$ v# B+ I# I#"), "L$ I%");
+    // inlining the string three times would grow the program
+    assert_eq!(
+        simplified("B$ L# B. v# B. v# v# SAAAAAAAAAA"),
+        "B$ L# B. v# B. v# v# SAAAAAAAAAA"
+    );
+    assert_eq!(simplified("B$ L# B. v# v# SAAAA"), "SAAAAAAAA");
+}
+
+#[test]
+fn simplify_preserves_results() {
+    use crate::parser::{encode_token, eval, parse_string};
+
+    let programs = [
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+        r#"B$ L# B$ L" B+ v" v" B* I$ I# v8"#,
+        r#"B$ B$ L# L$ v# B. SB%,,/ S}Q/2,$_ IK"#,
+    ];
+    for program in programs {
+        let program = parse_string(program);
+        let simplified = simplify(&program);
+        assert!(encode_token(&simplified).len() <= encode_token(&program).len());
+        assert_eq!(eval(&simplified), eval(&program));
+    }
+
+    for i in 1..=13 {
+        let input =
+            std::fs::read_to_string(format!("../efficiency/efficiency{:02}.in", i)).unwrap();
+        let simplified = encode_token(&simplify(&parse_string(&input)));
+        assert!(simplified.len() <= input.trim().len());
+    }
+}
+
+#[test]
+fn simplify_deep() {
+    use crate::parser::{encode_token, parse_string};
+    use num_bigint::BigInt;
+
+    let depth = 10_000;
+    let sum = "B+ v# ".repeat(depth) + "I!";
+    let program = parse_string(&format!("B$ L# {} I\"", sum));
+    assert_eq!(
+        encode_token(&simplify(&program)),
+        encode_token(&Token::Int(BigInt::from(depth)))
+    );
+}
@@ -7,13 +7,18 @@ use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::default;
 use std::io::Write;
 use std::ops::{Range, RangeInclusive};
+use std::path::Path;
 use std::rc::Rc;
 use std::time::Instant;
 use tokio::task;
 
 use crate::local_solver::LocalSolver;
 use crate::tsp::solve_tsp;
-use crate::{protocol, TEST_ID};
+use crate::{
+    protocol::{Client, ProtocolError, SubmissionQueue},
+    scores::ScoresDb,
+    workspace::{Source, Workspace},
+};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Point {
@@ -53,9 +58,11 @@ impl std::ops::AddAssign for Point {
     }
 }
 
-pub fn read_input(id: usize) -> Vec<Point> {
-    let filename = format!("../spaceship/spaceship{:02}.in", id);
-    let input = std::fs::read_to_string(filename).unwrap();
+pub fn read_input(ws: &Workspace, id: usize) -> Vec<Point> {
+    parse_input(&std::fs::read_to_string(ws.input("spaceship", id)).unwrap())
+}
+
+pub(crate) fn parse_input(input: &str) -> Vec<Point> {
     input
         .lines()
         .filter_map(|line| {
@@ -75,26 +82,24 @@ fn conv_dir(c: u8) -> Point {
     Point { x, y }
 }
 
-pub fn read_solution(id: usize) -> Vec<Point> {
-    {
-        let gena_path = format!("../gena/{:02}.out", id);
-        if let Ok(input) = std::fs::read_to_string(gena_path) {
-            let input: Vec<_> = input.split_whitespace().collect();
-            let input = input[input.len() - 1];
+pub fn read_solution(ws: &Workspace, id: usize) -> Vec<Point> {
+    for source in [
+        Source::Author("gena"),
+        Source::Author("borys"),
+        Source::Team,
+    ] {
+        if let Some(path) = ws.find_solution("spaceship", id, source) {
+            eprintln!("Reading from file: {:?}", path);
+            let input = std::fs::read_to_string(path).unwrap();
+            // gena's files start with the score
+            let input = input.split_whitespace().last().unwrap_or("");
             return input.bytes().map(conv_dir).collect();
         }
     }
-    for suffix in ["_borys", ""].iter() {
-        let filename = format!("../spaceship/spaceship{:02}{suffix}.out", id);
-        eprintln!("Reading from file: {:?}", filename);
-        if let Ok(input) = std::fs::read_to_string(filename) {
-            return input.trim().bytes().map(conv_dir).collect();
-        }
-    }
     panic!("No solution found");
 }
 
-pub(crate) fn check_solution_and_save(pts: &[Point], solution: &[Point], vis_file: &str) {
+pub(crate) fn check_solution_and_save(pts: &[Point], solution: &[Point], vis_file: &Path) {
     let mut need_to_visit: HashSet<Point> = pts.iter().cloned().collect();
     let mut f = std::fs::File::create(vis_file).unwrap();
     {
@@ -123,8 +128,24 @@ pub(crate) fn check_solution_and_save(pts: &[Point], solution: &[Point], vis_fil
 }
 
 pub(crate) fn check_solution(pts: &[Point], solution: &[Point]) {
+    eprintln!(
+        "Total need visit: {}",
+        pts.iter().collect::<HashSet<_>>().len()
+    );
+    assert_eq!(count_unvisited(pts, solution), 0)
+}
+
+/// The number of points the moves miss, `None` if a move is not a digit 1-9.
+pub(crate) fn check_moves(pts: &[Point], moves: &str) -> Option<usize> {
+    if !moves.bytes().all(|c| (b'1'..=b'9').contains(&c)) {
+        return None;
+    }
+    let solution: Vec<_> = moves.bytes().map(conv_dir).collect();
+    Some(count_unvisited(pts, &solution))
+}
+
+fn count_unvisited(pts: &[Point], solution: &[Point]) -> usize {
     let mut need_to_visit: HashSet<Point> = pts.iter().cloned().collect();
-    eprintln!("Total need visit: {}", need_to_visit.len());
     let mut pos = Point { x: 0, y: 0 };
     let mut velocity = Point { x: 0, y: 0 };
     need_to_visit.remove(&pos);
@@ -133,21 +154,19 @@ pub(crate) fn check_solution(pts: &[Point], solution: &[Point]) {
         pos += velocity;
         need_to_visit.remove(&pos);
     }
-    assert!(need_to_visit.is_empty())
+    need_to_visit.len()
 }
 
-pub fn spaceship_draw() {
-    for task_id in 1..=25 {
-        eprintln!("Task: {}", task_id);
-        let pts = read_input(task_id);
-        // eprintln!("Points: {:?}", pts);
-        let solution = read_solution(task_id);
+pub fn spaceship_draw(ws: &Workspace, task_id: usize) {
+    eprintln!("Task: {}", task_id);
+    let pts = read_input(ws, task_id);
+    // eprintln!("Points: {:?}", pts);
+    let solution = read_solution(ws, task_id);
 
-        eprintln!("Need to visit {}, sol len: {}", pts.len(), solution.len());
-        // eprintln!("Solution: {:?}", solution);
-        let vis_file = format!("../spaceship/spaceship{:02}.viz", task_id);
-        check_solution_and_save(&pts, &solution, &vis_file);
-    }
+    eprintln!("Need to visit {}, sol len: {}", pts.len(), solution.len());
+    // eprintln!("Solution: {:?}", solution);
+    let vis_file = ws.task_file("spaceship", task_id, ".viz");
+    check_solution_and_save(&pts, &solution, &vis_file);
 }
 
 const MAX_VELOCITY: i64 = 20;
@@ -294,8 +313,8 @@ fn convert_solution(sol: &[Point]) -> String {
     std::str::from_utf8(&s).unwrap().to_string()
 }
 
-fn save_solution(test_id: usize, sol: &[Point]) {
-    let filename = format!("../spaceship/spaceship{:02}_borys.out", test_id);
+fn save_solution(ws: &Workspace, test_id: usize, sol: &[Point]) {
+    let filename = ws.solution("spaceship", test_id, Source::Author("borys"));
     std::fs::write(filename, convert_solution(sol)).unwrap();
 }
 
@@ -465,19 +484,36 @@ fn solve_fixed_perm_precalc(pts: &[Point], precalc: &Precalc) -> Vec<Point> {
     res
 }
 
-fn run_python_viz(test_id: usize) {
+fn run_python_viz(ws: &Workspace, test_id: usize) {
     std::process::Command::new("python3")
-        .arg("../spaceship/draw_sol.py")
+        .arg(ws.family_dir("spaceship").join("draw_sol.py"))
         .arg(test_id.to_string())
         .output()
         .unwrap();
 }
 
-async fn send_solution(test_id: usize, sol: &[Point]) {
+pub async fn send_solution(ws: &Workspace, test_id: usize, sol: &[Point]) {
     let sol_str = convert_solution(sol);
-    let msg = format!("solve spaceship{test_id} {sol_str}");
-    eprintln!("MESSAGE:\n{msg}");
-    protocol::send_msg(&msg).await.unwrap();
+    let task = format!("spaceship{test_id}");
+    eprintln!("MESSAGE:\nsolve {task} {sol_str}");
+    let submit = async {
+        let scores = ScoresDb::open(ws.scores())?;
+        if !scores.is_better(&task, sol_str.len() as u64) {
+            return Ok(format!(
+                "not submitted, {:?} is not better",
+                scores.get(&task)
+            ));
+        }
+        let mut queue =
+            SubmissionQueue::from_env(Client::from_env()?, ws.queue_dir())?.with_scores(scores);
+        queue.push(&task, &sol_str)?;
+        queue.flush().await?;
+        Ok::<_, ProtocolError>(queue.verdict(&task).unwrap_or_default().to_string())
+    };
+    match submit.await {
+        Ok(verdict) => eprintln!("Response: {}", verdict),
+        Err(err) => eprintln!("Failed to submit {}: {}", task, err),
+    }
 }
 
 fn do_test() -> bool {
@@ -660,10 +696,10 @@ fn do_test2() -> bool {
     true
 }
 
-fn do_tsp(test_id: usize, pts: &[Point]) {
-    let order_filename = format!("../spaceship/spaceship{:02}_order.txt", test_id);
+fn do_tsp(ws: &Workspace, test_id: usize, pts: &[Point]) {
+    let order_filename = ws.task_file("spaceship", test_id, "_order.txt");
 
-    let order: Option<Vec<usize>> = if let Ok(input) = std::fs::read_to_string(order_filename) {
+    let order: Option<Vec<usize>> = if let Ok(input) = std::fs::read_to_string(&order_filename) {
         let mut order: Vec<usize> = input.lines().map(|line| line.parse().unwrap()).collect();
         order.remove(0);
         Some(order)
@@ -671,8 +707,7 @@ fn do_tsp(test_id: usize, pts: &[Point]) {
         None
     };
     let sol = solve_tsp(pts, order);
-    let mut f =
-        std::fs::File::create(format!("../spaceship/spaceship{:02}_order.txt", test_id)).unwrap();
+    let mut f = std::fs::File::create(&order_filename).unwrap();
     writeln!(f, "{}", sol.len()).unwrap();
     for id in sol {
         writeln!(f, "{}", id).unwrap();
@@ -763,7 +798,7 @@ fn calc_stats_old(pts: &[Point], sol: &[Point]) {
     // );
 }
 
-fn calc_stats(pts: &[Point], sol: &[Point], test_id: usize) {
+fn calc_stats(ws: &Workspace, pts: &[Point], sol: &[Point], test_id: usize) {
     let local_solver = LocalSolver::new();
 
     eprintln!("Sol len: {}", sol.len());
@@ -825,19 +860,16 @@ fn calc_stats(pts: &[Point], sol: &[Point], test_id: usize) {
         i += 1;
     }
 
-    let mut f =
-        std::fs::File::create(format!("../spaceship/spaceship{test_id}_order.txt")).unwrap();
+    let mut f = std::fs::File::create(ws.task_file("spaceship", test_id, "_order.txt")).unwrap();
     writeln!(f, "{}", real_order.len()).unwrap();
     for id in real_order {
         writeln!(f, "{}", id).unwrap();
     }
 }
 
-pub async fn spaceship_solve() -> bool {
+pub async fn spaceship_solve(ws: &Workspace, task_id: usize) -> bool {
     eprintln!("Hello");
 
-    let task_id = TEST_ID;
-
     // if do_test2() {
     //     return true;
     // }
@@ -847,14 +879,14 @@ pub async fn spaceship_solve() -> bool {
         let vis_file = "spaceship00.viz";
 
         eprintln!("Task: {}", task_id);
-        let pts = read_input(task_id);
+        let pts = read_input(ws, task_id);
 
         // eprintln!("Points: {:?}", pts);
         // for _ in 0..100 {
         // do_tsp(task_id, &pts);
         // }
-        let solution = read_solution(task_id);
-        calc_stats(&pts, &solution, task_id);
+        let solution = read_solution(ws, task_id);
+        calc_stats(ws, &pts, &solution, task_id);
 
         // let new_solution = solve(&pts, &solution, task_id, &vis_file);
         // check_solution(&pts, &solution);
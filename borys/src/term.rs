@@ -0,0 +1,308 @@
+use std::{collections::HashMap, ops::Index, rc::Rc};
+
+use num_bigint::BigInt;
+
+use crate::parser::{children, BinaryOp, Head, Token, UnaryOp};
+
+// Hash-consed ICFP terms: every distinct term is stored once in an `Arena`
+// and referred to by its `TermId`, so equal terms have equal ids and common
+// subterms (Y combinators, repeated checks) are shared. The simplifier works
+// on these, and `Strategy::Memo` keys its cache by the ids of the arguments.
+
+/// Index of a term in its `Arena`. Two ids from the same arena are equal
+/// iff the terms are structurally equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TermId(u32);
+
+impl TermId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A `Token` node whose children are interned.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Term {
+    Bool(bool),
+    Int(BigInt),
+    String(Vec<u8>),
+    UnaryOp(UnaryOp, TermId),
+    BinaryOp(BinaryOp, TermId, TermId),
+    If(TermId, TermId, TermId),
+    Application(TermId, TermId),
+    Abstraction(usize, TermId),
+    Id(usize),
+}
+
+impl Term {
+    pub(crate) fn head(&self) -> Head<'_> {
+        match self {
+            Term::Bool(b) => Head::Bool(*b),
+            Term::Int(i) => Head::Int(i),
+            Term::String(s) => Head::String(s),
+            Term::UnaryOp(op, _) => Head::UnaryOp(op),
+            Term::BinaryOp(op, _, _) => Head::BinaryOp(op),
+            Term::If(..) => Head::If,
+            Term::Application(..) => Head::Application,
+            Term::Abstraction(var, _) => Head::Abstraction(*var),
+            Term::Id(var) => Head::Id(*var),
+        }
+    }
+
+    pub fn children(&self) -> Vec<TermId> {
+        match self {
+            Term::Bool(_) | Term::Int(_) | Term::String(_) | Term::Id(_) => vec![],
+            Term::UnaryOp(_, inner) | Term::Abstraction(_, inner) => vec![*inner],
+            Term::BinaryOp(_, first, second) | Term::Application(first, second) => {
+                vec![*first, *second]
+            }
+            Term::If(cond, first, second) => vec![*cond, *first, *second],
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Arena {
+    terms: Vec<Term>,
+    ids: HashMap<Term, TermId>,
+    // sorted free variables of each term, shared with a child when equal
+    free: Vec<Rc<[usize]>>,
+    // length of each term's encoding, saturated for huge shared terms
+    sizes: Vec<usize>,
+    // `Token` of each term, built on demand so that equal terms share an `Rc`
+    tokens: Vec<Option<Rc<Token>>>,
+}
+
+impl Index<TermId> for Arena {
+    type Output = Term;
+
+    fn index(&self, id: TermId) -> &Term {
+        &self.terms[id.index()]
+    }
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, term: Term) -> TermId {
+        if let Some(&id) = self.ids.get(&term) {
+            return id;
+        }
+        let id = TermId(self.terms.len().try_into().expect("Too many terms"));
+        let free = match &term {
+            Term::Id(var) => Rc::from([*var]),
+            Term::Abstraction(var, body) => {
+                let body = &self.free[body.index()];
+                if body.binary_search(var).is_ok() {
+                    body.iter().copied().filter(|v| v != var).collect()
+                } else {
+                    body.clone()
+                }
+            }
+            _ => {
+                let sets: Vec<_> = term
+                    .children()
+                    .into_iter()
+                    .map(|c| &self.free[c.index()])
+                    .collect();
+                let mut union: Vec<usize> = sets.iter().flat_map(|s| s.iter().copied()).collect();
+                union.sort_unstable();
+                union.dedup();
+                match sets.iter().find(|s| s[..] == union[..]) {
+                    Some(&set) => set.clone(),
+                    None => Rc::from(union),
+                }
+            }
+        };
+        let mut head = vec![];
+        term.head().encode(&mut head);
+        let size = term.children().into_iter().fold(head.len(), |size, c| {
+            size.saturating_add(self.sizes[c.index()] + 1)
+        });
+        self.free.push(free);
+        self.sizes.push(size);
+        self.terms.push(term.clone());
+        self.ids.insert(term, id);
+        id
+    }
+
+    /// Free variables of the term, sorted.
+    pub fn free_vars(&self, id: TermId) -> &[usize] {
+        &self.free[id.index()]
+    }
+
+    /// Length of `encode(id)`, without encoding the term.
+    pub fn size(&self, id: TermId) -> usize {
+        self.sizes[id.index()]
+    }
+
+    /// Interns `token` and all its subterms. Shared `Rc`s are visited once.
+    pub fn intern_token(&mut self, token: &Token) -> TermId {
+        let mut ids: HashMap<*const Token, TermId> = HashMap::new();
+        let mut stack = vec![token];
+        while let Some(&token) = stack.last() {
+            if ids.contains_key(&(token as *const Token)) {
+                stack.pop();
+                continue;
+            }
+            let missing: Vec<&Token> = children(token)
+                .into_iter()
+                .map(|c| c.as_ref())
+                .filter(|&c| !ids.contains_key(&(c as *const Token)))
+                .collect();
+            if !missing.is_empty() {
+                stack.extend(missing);
+                continue;
+            }
+            let id = |c: &Rc<Token>| ids[&Rc::as_ptr(c)];
+            let term = match token {
+                Token::Bool(b) => Term::Bool(*b),
+                Token::Int(i) => Term::Int(i.clone()),
+                Token::String(s) => Term::String(s.clone()),
+                Token::UnaryOp(op, inner) => Term::UnaryOp(op.clone(), id(inner)),
+                Token::BinaryOp(op, first, second) => {
+                    Term::BinaryOp(op.clone(), id(first), id(second))
+                }
+                Token::If(cond, first, second) => Term::If(id(cond), id(first), id(second)),
+                Token::Application(f, arg) => Term::Application(id(f), id(arg)),
+                Token::Abstraction(var, body) => Term::Abstraction(*var, id(body)),
+                Token::Id(var) => Term::Id(*var),
+            };
+            let term = self.intern(term);
+            ids.insert(token, term);
+            stack.pop();
+        }
+        ids[&(token as *const Token)]
+    }
+
+    /// The term as a `Token` tree in which equal subterms are the same `Rc`.
+    pub fn token(&mut self, id: TermId) -> Rc<Token> {
+        self.tokens.resize(self.terms.len(), None);
+        let mut stack = vec![id];
+        while let Some(&id) = stack.last() {
+            if self.tokens[id.index()].is_some() {
+                stack.pop();
+                continue;
+            }
+            let missing: Vec<TermId> = self[id]
+                .children()
+                .into_iter()
+                .filter(|c| self.tokens[c.index()].is_none())
+                .collect();
+            if !missing.is_empty() {
+                stack.extend(missing);
+                continue;
+            }
+            let token = |c: &TermId| self.tokens[c.index()].clone().unwrap();
+            let res = match &self[id] {
+                Term::Bool(b) => Token::Bool(*b),
+                Term::Int(i) => Token::Int(i.clone()),
+                Term::String(s) => Token::String(s.clone()),
+                Term::UnaryOp(op, inner) => Token::UnaryOp(op.clone(), token(inner)),
+                Term::BinaryOp(op, first, second) => {
+                    Token::BinaryOp(op.clone(), token(first), token(second))
+                }
+                Term::If(cond, first, second) => {
+                    Token::If(token(cond), token(first), token(second))
+                }
+                Term::Application(f, arg) => Token::Application(token(f), token(arg)),
+                Term::Abstraction(var, body) => Token::Abstraction(*var, token(body)),
+                Term::Id(var) => Token::Id(*var),
+            };
+            self.tokens[id.index()] = Some(Rc::new(res));
+            stack.pop();
+        }
+        self.tokens[id.index()].clone().unwrap()
+    }
+
+    /// Serializes the term to ICFP text, same as `encode_token`.
+    #[cfg(test)]
+    pub fn encode(&self, id: TermId) -> String {
+        let mut res = vec![];
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if !res.is_empty() {
+                res.push(b' ');
+            }
+            let term = &self[id];
+            term.head().encode(&mut res);
+            stack.extend(term.children().into_iter().rev());
+        }
+        String::from_utf8(res).unwrap()
+    }
+}
+
+#[test]
+fn intern_simple() {
+    use crate::parser::parse_string;
+
+    let mut arena = Arena::new();
+    // the two halves of the Y combinator are the same term
+    let fact = parse_string(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+    );
+    let id = arena.intern_token(&fact);
+    let Term::Application(fix, _) = arena[id] else {
+        panic!("Expected application");
+    };
+    let Term::Application(y, _) = arena[fix] else {
+        panic!("Expected application");
+    };
+    let Term::Abstraction(_, body) = arena[y] else {
+        panic!("Expected abstraction");
+    };
+    let Term::Application(first, second) = arena[body] else {
+        panic!("Expected application");
+    };
+    assert_eq!(first, second);
+    assert_eq!(arena.free_vars(first), [1]);
+    assert_eq!(arena.free_vars(body), [1]);
+    assert!(arena.free_vars(y).is_empty());
+    assert!(arena.free_vars(id).is_empty());
+
+    // the root is the last new term
+    assert_eq!(arena.intern_token(&fact), id);
+    assert_eq!(arena.intern(Term::Id(9)).index(), id.index() + 1);
+
+    let token = arena.token(id);
+    assert_eq!(*token, fact);
+    let Token::Application(fix, _) = token.as_ref() else {
+        unreachable!();
+    };
+    let Token::Application(y, _) = fix.as_ref() else {
+        unreachable!();
+    };
+    let Token::Abstraction(_, body) = y.as_ref() else {
+        unreachable!();
+    };
+    let Token::Application(first, second) = body.as_ref() else {
+        unreachable!();
+    };
+    assert!(Rc::ptr_eq(first, second));
+
+    let id = arena.intern(Term::Id(3));
+    let abstraction = arena.intern(Term::Abstraction(3, id));
+    let application = arena.intern(Term::Application(abstraction, id));
+    assert_eq!(arena.free_vars(abstraction), [] as [usize; 0]);
+    assert_eq!(arena.free_vars(application), [3]);
+}
+
+#[test]
+fn intern_efficiency() {
+    use crate::parser::{encode_token, parse_string};
+
+    for i in 1..=13 {
+        let input =
+            std::fs::read_to_string(format!("../efficiency/efficiency{:02}.in", i)).unwrap();
+        let token = parse_string(&input);
+        let mut arena = Arena::new();
+        let id = arena.intern_token(&token);
+        let nodes = input.split_whitespace().count();
+        assert!(id.index() < nodes);
+        assert_eq!(arena.encode(id), encode_token(&token));
+        assert_eq!(arena.size(id), encode_token(&token).len());
+        assert_eq!(*arena.token(id), token);
+    }
+}
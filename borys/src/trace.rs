@@ -0,0 +1,160 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    ops::Range,
+};
+
+use crate::{decompiler::decompile_truncated, parser::Token};
+
+// Log of the reduction steps of an evaluation, one line per step:
+//
+//     <step> <depth> <kind> <redex>
+//
+// Steps are numbered from 0 and counted whether they are written or not.
+// The depth is the number of pending continuations. Redexes are decompiled
+// only for the written steps.
+
+/// Redexes longer than this are cut.
+const REDEX_WIDTH: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepKind {
+    Beta,
+    Unary,
+    Binary,
+    /// The branch taken.
+    If(bool),
+}
+
+impl fmt::Display for StepKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepKind::Beta => write!(f, "beta"),
+            StepKind::Unary => write!(f, "unary"),
+            StepKind::Binary => write!(f, "binary"),
+            StepKind::If(true) => write!(f, "then"),
+            StepKind::If(false) => write!(f, "else"),
+        }
+    }
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    pub depths: Option<Range<usize>>,
+    pub steps: Option<Range<usize>>,
+    pub step: usize,
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("depths", &self.depths)
+            .field("steps", &self.steps)
+            .field("step", &self.step)
+            .finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static) -> Self {
+        Self {
+            out: Box::new(out),
+            depths: None,
+            steps: None,
+            step: 0,
+            error: None,
+        }
+    }
+
+    pub fn with_depths(mut self, depths: Range<usize>) -> Self {
+        self.depths = Some(depths);
+        self
+    }
+
+    pub fn with_steps(mut self, steps: Range<usize>) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    pub(crate) fn record(&mut self, kind: StepKind, depth: usize, redex: impl FnOnce() -> Token) {
+        let step = self.step;
+        self.step += 1;
+        let enabled =
+            |range: &Option<Range<usize>>, i| range.as_ref().is_none_or(|r| r.contains(&i));
+        if self.error.is_some() || !enabled(&self.steps, step) || !enabled(&self.depths, depth) {
+            return;
+        }
+        let redex = decompile_truncated(&redex(), REDEX_WIDTH);
+        if let Err(err) = writeln!(self.out, "{} {} {} {}", step, depth, kind, redex) {
+            self.error = Some(err);
+        }
+    }
+
+    /// Flushes the output and returns the first write error, later steps are
+    /// not written after one.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// Parses `from..to`, either end can be omitted.
+pub fn parse_range(s: &str) -> Option<Range<usize>> {
+    let (from, to) = s.split_once("..")?;
+    let bound = |s: &str, default| match s.trim() {
+        "" => Some(default),
+        s => s.parse().ok(),
+    };
+    Some(bound(from, 0)?..bound(to, usize::MAX)?)
+}
+
+#[test]
+fn trace_fact() {
+    use crate::parser::{parse_string, EvalContext, Strategy};
+
+    let fact = parse_string(
+        r#"B$ B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# L" L# ? B= v# I" I" B* v# B$ v" B- v# I" I&"#,
+    );
+    let path = std::env::temp_dir().join(format!("trace_fact_{}.log", std::process::id()));
+    let trace = |strategy, tracer: Tracer| {
+        let mut ctx = EvalContext::new(strategy).with_tracer(tracer);
+        let res = ctx.eval(&fact).unwrap();
+        assert_eq!(res, Token::Int(120.into()));
+        ctx.tracer.unwrap().borrow_mut().finish().unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        (ctx.beta_reductions, log)
+    };
+    let file = || std::fs::File::create(&path).unwrap();
+
+    for strategy in [Strategy::Name, Strategy::Need] {
+        let (beta_reductions, log) = trace(strategy, Tracer::new(file()));
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0 1 beta let rec v1 v2 = if v2 == 1 then 1 else v2 * v1 (v2 - 1) in v1"
+        );
+        // depths differ between the strategies
+        assert!(lines[4].starts_with("4 ") && lines[4].ends_with(" binary 5 == 1"));
+        assert!(lines[5].starts_with("5 ") && lines[5].contains(" else if false then 1 else "));
+        let betas = lines.iter().filter(|line| line.contains(" beta ")).count();
+        assert_eq!(betas, beta_reductions);
+        for (i, line) in lines.iter().enumerate() {
+            assert!(line.starts_with(&format!("{} ", i)));
+        }
+    }
+
+    let (_, log) = trace(Strategy::Need, Tracer::new(file()).with_steps(4..6));
+    assert_eq!(log.lines().count(), 2);
+    let (_, log) = trace(Strategy::Need, Tracer::new(file()).with_depths(0..1));
+    assert!(log.lines().all(|line| line.split(' ').nth(1) == Some("0")));
+    assert!(log.starts_with("3 0 beta let v2 = 5 in "));
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(parse_range("3..10"), Some(3..10));
+    assert_eq!(parse_range("..10"), Some(0..10));
+    assert_eq!(parse_range("3.."), Some(3..usize::MAX));
+    assert_eq!(parse_range("3"), None);
+}
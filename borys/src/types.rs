@@ -0,0 +1,496 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+    decompiler::decompile_truncated,
+    parser::{offset_of, BinaryOp, Token, UnaryOp},
+};
+
+// Hindley-Milner type inference for ICFP programs. `B$ (L x. body) value` is
+// typed as `let x = value in body`, so `x` can be used at different types.
+// Self-application can't be typed, so recognised fixpoint combinators get the
+// type `('a -> 'a) -> 'a`. `==` accepts any two values of the same type.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Bool,
+    String,
+    Var(usize),
+    Fun(Rc<Type>, Rc<Type>),
+}
+
+fn fun(param: Type, res: Type) -> Type {
+    Type::Fun(Rc::new(param), Rc::new(res))
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::String => write!(f, "string"),
+            Type::Var(i) if *i < 26 => write!(f, "'{}", (b'a' + *i as u8) as char),
+            Type::Var(i) => write!(f, "'t{}", i),
+            Type::Fun(param, res) => match param.as_ref() {
+                Type::Fun(..) => write!(f, "({}) -> {}", param, res),
+                _ => write!(f, "{} -> {}", param, res),
+            },
+        }
+    }
+}
+
+/// The first subterm whose type doesn't match its use.
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub expected: Type,
+    pub got: Type,
+    pub expr: Token,
+    pub offset: Option<usize>,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = match self.offset {
+            Some(offset) => format!("token {}", offset),
+            None => "unknown token".to_string(),
+        };
+        let expr = decompile_truncated(&self.expr, 60);
+        write!(
+            f,
+            "Type error at {}: expected {}, got {} in {}",
+            offset, self.expected, self.got, expr
+        )
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// Infers the type of `token`, type variables are numbered from 0.
+pub fn infer(token: &Token) -> Result<Type, TypeError> {
+    let mut inference = Inference {
+        root: token,
+        bound: vec![],
+        free: HashMap::new(),
+    };
+    let res = inference.infer(token).map_err(|err| *err)?;
+    Ok(normalize(&[inference.resolve(&res)]).remove(0))
+}
+
+/// Checks that `program` can evaluate to a string, as every message to the
+/// server must. A program of unknown type passes.
+pub fn check_message(program: &Token) -> Result<(), TypeError> {
+    match infer(program)? {
+        Type::String | Type::Var(_) => Ok(()),
+        got => Err(TypeError {
+            expected: Type::String,
+            got,
+            expr: program.clone(),
+            offset: Some(0),
+        }),
+    }
+}
+
+// Renames the type variables to 0, 1, ... in the order of appearance.
+fn normalize(types: &[Type]) -> Vec<Type> {
+    let mut names = HashMap::new();
+    let mut rename = |t: &Type| match t {
+        Type::Var(v) => {
+            let len = names.len();
+            Type::Var(*names.entry(*v).or_insert(len))
+        }
+        _ => t.clone(),
+    };
+    types.iter().map(|t| map_type(t, &mut rename)).collect()
+}
+
+// Rebuilds `t` with `f` applied to every node before its parameter and
+// result, on an explicit stack as the types of deep programs are deep too.
+fn map_type(t: &Type, mut f: impl FnMut(&Type) -> Type) -> Type {
+    enum Task {
+        Visit(Type),
+        Fun,
+    }
+    let mut tasks = vec![Task::Visit(t.clone())];
+    let mut results = vec![];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(t) => match f(&t) {
+                Type::Fun(param, res) => {
+                    tasks.push(Task::Fun);
+                    tasks.push(Task::Visit(res.as_ref().clone()));
+                    tasks.push(Task::Visit(param.as_ref().clone()));
+                }
+                t => results.push(t),
+            },
+            Task::Fun => {
+                let res = results.pop().unwrap();
+                let param = results.pop().unwrap();
+                results.push(fun(param, res));
+            }
+        }
+    }
+    results.pop().unwrap()
+}
+
+// A type whose `vars` can be instantiated with any types.
+#[derive(Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Type,
+}
+
+// Schemes of the bound variables, the last binding of a variable wins.
+type Env = Vec<(usize, Scheme)>;
+
+struct Inference<'a> {
+    root: &'a Token,
+    // types the type variables were unified with
+    bound: Vec<Option<Type>>,
+    // types of the variables free in the program
+    free: HashMap<usize, Type>,
+}
+
+impl<'a> Inference<'a> {
+    fn fresh(&mut self) -> Type {
+        self.bound.push(None);
+        Type::Var(self.bound.len() - 1)
+    }
+
+    // Follows the bound variables at the top of `t`.
+    fn prune(&self, t: &Type) -> Type {
+        let mut t = t.clone();
+        while let Type::Var(v) = t {
+            match &self.bound[v] {
+                Some(bound) => t = bound.clone(),
+                None => break,
+            }
+        }
+        t
+    }
+
+    fn resolve(&self, t: &Type) -> Type {
+        map_type(t, |t| self.prune(t))
+    }
+
+    fn type_vars(&self, t: &Type, res: &mut Vec<usize>) {
+        let mut stack = vec![t.clone()];
+        while let Some(t) = stack.pop() {
+            match self.prune(&t) {
+                Type::Var(v) => res.push(v),
+                Type::Fun(param, res_type) => {
+                    stack.push(res_type.as_ref().clone());
+                    stack.push(param.as_ref().clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let mut stack = vec![(a.clone(), b.clone())];
+        while let Some((a, b)) = stack.pop() {
+            match (self.prune(&a), self.prune(&b)) {
+                (Type::Var(a), Type::Var(b)) if a == b => {}
+                (Type::Var(v), t) | (t, Type::Var(v)) => {
+                    let mut vars = vec![];
+                    self.type_vars(&t, &mut vars);
+                    if vars.contains(&v) {
+                        return false;
+                    }
+                    self.bound[v] = Some(t);
+                }
+                (Type::Fun(p1, r1), Type::Fun(p2, r2)) => {
+                    stack.push((r1.as_ref().clone(), r2.as_ref().clone()));
+                    stack.push((p1.as_ref().clone(), p2.as_ref().clone()));
+                }
+                (a, b) if a == b => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // Unifies the type `got` of `expr` with `expected`.
+    fn expect(&mut self, expr: &Token, expected: Type, got: &Type) -> Result<(), Box<TypeError>> {
+        if self.unify(&expected, got) {
+            return Ok(());
+        }
+        let types = normalize(&[self.resolve(&expected), self.resolve(got)]);
+        Err(Box::new(TypeError {
+            expected: types[0].clone(),
+            got: types[1].clone(),
+            expr: expr.clone(),
+            offset: offset_of(self.root, expr),
+        }))
+    }
+
+    fn generalize(&self, t: &Type, env: &Env) -> Scheme {
+        let mut env_vars = vec![];
+        for (_, scheme) in env {
+            self.type_vars(&scheme.ty, &mut env_vars);
+        }
+        let mut vars = vec![];
+        self.type_vars(t, &mut vars);
+        vars.retain(|v| !env_vars.contains(v));
+        vars.sort_unstable();
+        vars.dedup();
+        Scheme {
+            vars,
+            ty: self.resolve(t),
+        }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let fresh: HashMap<_, _> = scheme.vars.iter().map(|&v| (v, self.fresh())).collect();
+        map_type(&scheme.ty, |t| match t {
+            Type::Var(v) => fresh.get(v).cloned().unwrap_or(Type::Var(*v)),
+            _ => t.clone(),
+        })
+    }
+
+    // On an explicit stack, as programs can be deeper than the Rust stack.
+    fn infer(&mut self, token: &'a Token) -> Result<Type, Box<TypeError>> {
+        let mut env: Env = vec![];
+        let mut tasks = vec![Task::Visit(token)];
+        let mut results: Vec<Type> = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(token) => self.visit(token, &mut env, &mut tasks, &mut results),
+                Task::Expect(expr, expected) => {
+                    let t = results.pop().unwrap();
+                    self.expect(expr, expected, &t)?;
+                }
+                Task::Push(t) => results.push(t),
+                Task::Branches(second) => {
+                    let t2 = results.pop().unwrap();
+                    let t1 = results.last().unwrap().clone();
+                    self.expect(second, t1, &t2)?;
+                }
+                Task::Bind(var) => {
+                    let t = results.pop().unwrap();
+                    let scheme = self.generalize(&t, &env);
+                    env.push((var, scheme));
+                }
+                Task::Unbind => {
+                    env.pop();
+                }
+                Task::Fun(param) => {
+                    let res = results.pop().unwrap();
+                    results.push(fun(param, res));
+                }
+                Task::Apply(f, value) => {
+                    let t = results.pop().unwrap();
+                    let tf = results.pop().unwrap();
+                    // blame the argument when the function is known
+                    if let Type::Fun(param, _) = self.prune(&tf) {
+                        self.expect(value, param.as_ref().clone(), &t)?;
+                    }
+                    let res = self.fresh();
+                    self.expect(f, fun(t, res.clone()), &tf)?;
+                    results.push(res);
+                }
+            }
+        }
+        Ok(results.pop().unwrap())
+    }
+
+    // Pushes the type of a leaf, or the tasks computing the type of `token`
+    // in reverse order.
+    fn visit(
+        &mut self,
+        token: &'a Token,
+        env: &mut Env,
+        tasks: &mut Vec<Task<'a>>,
+        results: &mut Vec<Type>,
+    ) {
+        match token {
+            Token::Bool(_) => results.push(Type::Bool),
+            Token::Int(_) => results.push(Type::Int),
+            Token::String(_) => results.push(Type::String),
+            Token::Id(i) => {
+                let t = self.var(*i, env);
+                results.push(t);
+            }
+            Token::UnaryOp(op, inner) => {
+                let (param, res) = match op {
+                    UnaryOp::NegInteger => (Type::Int, Type::Int),
+                    UnaryOp::Not => (Type::Bool, Type::Bool),
+                    UnaryOp::StringToInt => (Type::String, Type::Int),
+                    UnaryOp::IntToString => (Type::Int, Type::String),
+                };
+                tasks.push(Task::Push(res));
+                tasks.push(Task::Expect(inner, param));
+                tasks.push(Task::Visit(inner));
+            }
+            Token::BinaryOp(op, first, second) => {
+                let (p1, p2, res) = match op {
+                    BinaryOp::Add
+                    | BinaryOp::Sub
+                    | BinaryOp::Mul
+                    | BinaryOp::Div
+                    | BinaryOp::Mod => (Type::Int, Type::Int, Type::Int),
+                    BinaryOp::Less | BinaryOp::More => (Type::Int, Type::Int, Type::Bool),
+                    BinaryOp::Eq => {
+                        let t = self.fresh();
+                        (t.clone(), t, Type::Bool)
+                    }
+                    BinaryOp::Or | BinaryOp::And => (Type::Bool, Type::Bool, Type::Bool),
+                    BinaryOp::Concat => (Type::String, Type::String, Type::String),
+                    BinaryOp::Prefix | BinaryOp::Drop => (Type::Int, Type::String, Type::String),
+                };
+                tasks.push(Task::Push(res));
+                tasks.push(Task::Expect(second, p2));
+                tasks.push(Task::Visit(second));
+                tasks.push(Task::Expect(first, p1));
+                tasks.push(Task::Visit(first));
+            }
+            Token::If(cond, first, second) => {
+                tasks.push(Task::Branches(second));
+                tasks.push(Task::Visit(second));
+                tasks.push(Task::Visit(first));
+                tasks.push(Task::Expect(cond, Type::Bool));
+                tasks.push(Task::Visit(cond));
+            }
+            Token::Abstraction(..) if token.is_fix_combinator() => {
+                let t = self.fresh();
+                results.push(fun(fun(t.clone(), t.clone()), t));
+            }
+            Token::Abstraction(var, body) => {
+                let param = self.fresh();
+                let scheme = Scheme {
+                    vars: vec![],
+                    ty: param.clone(),
+                };
+                env.push((*var, scheme));
+                tasks.push(Task::Fun(param));
+                tasks.push(Task::Unbind);
+                tasks.push(Task::Visit(body));
+            }
+            Token::Application(f, value) => match f.as_ref() {
+                Token::Abstraction(var, body) if !f.is_fix_combinator() => {
+                    tasks.push(Task::Unbind);
+                    tasks.push(Task::Visit(body));
+                    tasks.push(Task::Bind(*var));
+                    tasks.push(Task::Visit(value));
+                }
+                _ => {
+                    tasks.push(Task::Apply(f, value));
+                    tasks.push(Task::Visit(value));
+                    tasks.push(Task::Visit(f));
+                }
+            },
+        }
+    }
+
+    fn var(&mut self, var: usize, env: &Env) -> Type {
+        if let Some((_, scheme)) = env.iter().rev().find(|(v, _)| *v == var) {
+            let scheme = scheme.clone();
+            return self.instantiate(&scheme);
+        }
+        if let Some(t) = self.free.get(&var) {
+            return t.clone();
+        }
+        let t = self.fresh();
+        self.free.insert(var, t.clone());
+        t
+    }
+}
+
+// Steps of `Inference::infer`, the types of the visited terms are kept on a
+// separate stack.
+enum Task<'a> {
+    Visit(&'a Token),
+    // unify the type on top with the expected one
+    Expect(&'a Token, Type),
+    Push(Type),
+    // unify the type of the else branch on top with the one of the then branch
+    Branches(&'a Token),
+    // generalize the type on top and bind it to the variable
+    Bind(usize),
+    Unbind,
+    // the type on top is the result of a function taking the parameter
+    Fun(Type),
+    Apply(&'a Token, &'a Token),
+}
+
+#[test]
+fn infer_simple() {
+    use crate::{compiler::compile, parser::parse_string};
+
+    let type_of = |source: &str| infer(&compile(source).unwrap()).unwrap().to_string();
+    assert_eq!(type_of("1 + 2"), "int");
+    assert_eq!(type_of("fun x -> x"), "'a -> 'a");
+    assert_eq!(type_of("fun f x -> f (f x)"), "('a -> 'a) -> 'a -> 'a");
+    assert_eq!(
+        type_of("fun s n -> take n s ++ \"!\""),
+        "string -> int -> string"
+    );
+    assert_eq!(
+        type_of("let id x = x in if id true then id 1 else str_to_int (id \"c\")"),
+        "int"
+    );
+    assert_eq!(
+        type_of("let rec fact n = if n == 1 then 1 else n * fact (n - 1) in fact"),
+        "int -> int"
+    );
+    assert_eq!(type_of("fun x y -> x == y"), "'a -> 'a -> bool");
+    // free variables of the program
+    assert_eq!(infer(&parse_string("B+ v# v#")).unwrap(), Type::Int);
+
+    for i in 1..=13 {
+        let input =
+            std::fs::read_to_string(format!("../efficiency/efficiency{:02}.in", i)).unwrap();
+        let t = infer(&parse_string(&input)).unwrap();
+        eprintln!("efficiency{:02}: {}", i, t);
+        assert!(t == Type::Int || t == Type::Bool);
+    }
+    let input = std::fs::read_to_string("inputs/language_test.txt").unwrap();
+    assert_eq!(infer(&parse_string(&input)).unwrap(), Type::String);
+}
+
+#[test]
+fn infer_errors() {
+    use crate::{compiler::compile, parser::parse_string};
+
+    let error = |source: &str| infer(&compile(source).unwrap()).unwrap_err().to_string();
+    assert_eq!(
+        error("1 + \"a\""),
+        "Type error at token 2: expected int, got string in \"a\""
+    );
+    assert_eq!(
+        error("if 1 < 2 then 1 else \"a\""),
+        "Type error at token 5: expected int, got string in \"a\""
+    );
+    assert_eq!(
+        error("let f x = x + 1 in f true"),
+        "Type error at token 4: expected int, got bool in true"
+    );
+    assert_eq!(
+        error("let x = 5 in x 1"),
+        "Type error at token 3: expected int -> 'a, got int in v0"
+    );
+    assert_eq!(
+        error("fun x -> x x"),
+        "Type error at token 2: expected 'a -> 'b, got 'a in v0"
+    );
+    let err = infer(&parse_string("B$ L# B+ I\" B* v# I# S4%34")).unwrap_err();
+    // the use of the variable is blamed, not the value bound to it
+    assert_eq!((err.offset, err.got), (Some(5), Type::String));
+
+    assert!(check_message(&parse_string("B. S$ v#")).is_ok());
+    assert!(check_message(&parse_string("B$ L# v# v$")).is_ok());
+    let err = check_message(&parse_string("B+ I\" I\"")).unwrap_err();
+    assert_eq!((err.offset, err.got), (Some(0), Type::Int));
+}
+
+#[test]
+fn infer_deep() {
+    use crate::parser::parse_string;
+
+    let depth = 10_000;
+    let sum = "B+ v# ".repeat(depth) + "I!";
+    let program = parse_string(&format!("B$ L# {} I\"", sum));
+    assert_eq!(infer(&program).unwrap(), Type::Int);
+    let program = parse_string(&format!("B$ L# {} S!", sum));
+    assert_eq!(infer(&program).unwrap_err().offset, Some(3));
+}
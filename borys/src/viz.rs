@@ -1,7 +1,7 @@
 use crate::{
     spaceship::{self, Point},
+    workspace::Workspace,
     zoomer::{Zoomer, PIXELS_PER_POINT, SCREEN_H, SCREEN_W},
-    TEST_ID,
 };
 use eframe::egui::{CentralPanel, Color32, Context, Pos2, Stroke};
 use egui::{FontId, Vec2};
@@ -31,14 +31,13 @@ fn convert_sol_to_path(sol: &[Point]) -> Vec<Point> {
     path
 }
 
-impl Default for App {
-    fn default() -> Self {
-        let test_id = TEST_ID;
-        let input = spaceship::read_input(test_id);
+impl App {
+    fn new(ws: &Workspace, test_id: usize) -> Self {
+        let input = spaceship::read_input(ws, test_id);
         let zoomer = Zoomer::new(&input);
         zoomer.ensure_fits(&input);
 
-        let solution = spaceship::read_solution(test_id);
+        let solution = spaceship::read_solution(ws, test_id);
         // spaceship::check_solution(&input, &solution);
 
         Self {
@@ -204,7 +203,8 @@ impl eframe::App for App {
     }
 }
 
-pub fn viz_main() -> eframe::Result<()> {
+pub fn viz_main(ws: &Workspace, test_id: usize) -> eframe::Result<()> {
+    let ws = ws.clone();
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size((SCREEN_W, SCREEN_H)),
         ..eframe::NativeOptions::default()
@@ -213,6 +213,6 @@ pub fn viz_main() -> eframe::Result<()> {
     eframe::run_native(
         App::name(),
         native_options,
-        Box::new(|_| Box::new(App::default())),
+        Box::new(move |_| Box::new(App::new(&ws, test_id))),
     )
 }
@@ -2,8 +2,8 @@ use crate::{
     array_2d::Array2D,
     lambdaman::{self, VerySimpleRng, DEFAULT_STEPS_LIMIT},
     spaceship::{self, Point},
+    workspace::Workspace,
     zoomer::{Zoomer, PIXELS_PER_POINT, SCREEN_H, SCREEN_W},
-    TEST_ID,
 };
 use eframe::egui::{CentralPanel, Color32, Context, Pos2, Stroke};
 use egui::{FontId, Rect, Rounding, Vec2};
@@ -21,13 +21,9 @@ impl App {
     fn name() -> &'static str {
         "Lambdaman"
     }
-}
-
-impl Default for App {
-    fn default() -> Self {
-        let test_id = TEST_ID;
 
-        let task = lambdaman::read_task(test_id);
+    fn new(ws: &Workspace, test_id: usize) -> Self {
+        let task = lambdaman::read_task(ws, test_id);
         let n = task.walls.len();
         let m = task.walls[0].len();
         let input = vec![Point::new(0, 0), Point::new(n as i64, m as i64)];
@@ -132,7 +128,8 @@ impl eframe::App for App {
     }
 }
 
-pub fn viz_lambda_main() -> eframe::Result<()> {
+pub fn viz_lambda_main(ws: &Workspace, test_id: usize) -> eframe::Result<()> {
+    let ws = ws.clone();
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size((SCREEN_W, SCREEN_H)),
         ..eframe::NativeOptions::default()
@@ -141,6 +138,6 @@ pub fn viz_lambda_main() -> eframe::Result<()> {
     eframe::run_native(
         App::name(),
         native_options,
-        Box::new(|_| Box::new(App::default())),
+        Box::new(move |_| Box::new(App::new(&ws, test_id))),
     )
 }
@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+// Where the tasks, solutions and server state live. Files of a task are named
+// after its family and two digit id, e.g. in `<root>/spaceship/`:
+//
+//     spaceship07.in         the task
+//     spaceship07.icfp       the task as the server's program, when too costly
+//                            to evaluate
+//     spaceship07.out        the solution we submit
+//     spaceship07_gena.out   the solution of one author
+//     spaceship07.viz        anything else derived from the task
+//
+// Older solutions named `<family>/07.out`, and spaceship solutions named
+// `<author>/07.out`, are still found. The submission queue, the response
+// cache, the scores and the last scoreboard are kept in `<root>/submissions`,
+// `<root>/cache`, `<root>/scores.tsv` and `<root>/scoreboard.tsv`.
+
+pub const FAMILIES: [&str; 4] = ["spaceship", "lambdaman", "3d", "efficiency"];
+
+// The only family whose solutions were kept in the authors' directories.
+const AUTHOR_DIR_FAMILY: &str = "spaceship";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source<'a> {
+    /// The solution we submit.
+    Team,
+    Author(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Default for Workspace {
+    /// The directory containing the borys crate, wherever it is run from.
+    fn default() -> Self {
+        Self::new(Path::new(env!("CARGO_MANIFEST_DIR")).join(".."))
+    }
+}
+
+impl Workspace {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// `DATA_DIR` if set, the default root otherwise.
+    pub fn from_env() -> Self {
+        match dotenv::var("DATA_DIR") {
+            Ok(root) => Self::new(root),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn family_dir(&self, family: &str) -> PathBuf {
+        self.root.join(family)
+    }
+
+    /// `<root>/<family>/<family><id><suffix>`
+    pub fn task_file(&self, family: &str, id: usize, suffix: &str) -> PathBuf {
+        self.family_dir(family)
+            .join(format!("{}{:02}{}", family, id, suffix))
+    }
+
+    pub fn input(&self, family: &str, id: usize) -> PathBuf {
+        self.task_file(family, id, ".in")
+    }
+
+    pub fn program(&self, family: &str, id: usize) -> PathBuf {
+        self.task_file(family, id, ".icfp")
+    }
+
+    /// Where the solution is saved.
+    pub fn solution(&self, family: &str, id: usize, source: Source) -> PathBuf {
+        match source {
+            Source::Team => self.task_file(family, id, ".out"),
+            Source::Author(author) => self.task_file(family, id, &format!("_{}.out", author)),
+        }
+    }
+
+    /// The existing solution file, also under the older names.
+    pub fn find_solution(&self, family: &str, id: usize, source: Source) -> Option<PathBuf> {
+        let old = match source {
+            Source::Team => Some(self.family_dir(family)),
+            Source::Author(author) if family == AUTHOR_DIR_FAMILY => Some(self.root.join(author)),
+            Source::Author(_) => None,
+        }
+        .map(|dir| dir.join(format!("{:02}.out", id)));
+        [Some(self.solution(family, id, source)), old]
+            .into_iter()
+            .flatten()
+            .find(|path| path.exists())
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+
+    pub fn queue_dir(&self) -> PathBuf {
+        self.root.join("submissions")
+    }
+
+    pub fn scores(&self) -> PathBuf {
+        self.root.join("scores.tsv")
+    }
+
+    pub fn scoreboard(&self) -> PathBuf {
+        self.root.join("scoreboard.tsv")
+    }
+}
+
+#[test]
+fn workspace_paths() {
+    let ws = Workspace::new("/data");
+    assert_eq!(
+        ws.input("spaceship", 7),
+        Path::new("/data/spaceship/spaceship07.in")
+    );
+    assert_eq!(
+        ws.solution("lambdaman", 12, Source::Author("borys")),
+        Path::new("/data/lambdaman/lambdaman12_borys.out")
+    );
+    assert_eq!(
+        ws.task_file("3d", 3, ".viz"),
+        Path::new("/data/3d/3d03.viz")
+    );
+
+    // the checkout, whatever the current directory
+    let ws = Workspace::default();
+    assert!(ws.input("spaceship", 1).exists());
+    assert_eq!(
+        ws.find_solution("spaceship", 1, Source::Author("gena")),
+        Some(ws.root().join("gena/01.out"))
+    );
+    assert_eq!(
+        ws.find_solution("lambdaman", 5, Source::Team),
+        Some(ws.root().join("lambdaman/05.out"))
+    );
+    assert_eq!(
+        ws.find_solution("spaceship", 2, Source::Team),
+        Some(ws.input("spaceship", 2).with_extension("out"))
+    );
+    assert_eq!(
+        ws.find_solution("spaceship", 2, Source::Author("roma")),
+        None
+    );
+    // `gena/01.out` is the solution of spaceship01
+    assert_eq!(
+        ws.find_solution("lambdaman", 1, Source::Author("gena")),
+        None
+    );
+}